@@ -0,0 +1,320 @@
+use crate::config::{AuthConfig, AuthScheme, ConfigError};
+use crate::http::{Request, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+const REALM: &str = "metrics_generator";
+
+/// Checks the credentials of requests to the endpoints that want them
+pub struct Auth {
+    users: BTreeMap<String, String>,
+    endpoints: BTreeMap<String, Vec<AuthScheme>>,
+    tokens: Option<TokenFile>,
+}
+
+// the bearer tokens, read again whenever the file's modification time changes
+struct TokenFile {
+    path: PathBuf,
+    loaded: Mutex<Tokens>,
+}
+
+struct Tokens {
+    modified: Option<SystemTime>,
+    tokens: Vec<String>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Result<Auth, ConfigError> {
+        let tokens = match &config.bearer_token_file {
+            Some(path) => {
+                let loaded =
+                    read_tokens(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+                info!(
+                    tokens = loaded.tokens.len(),
+                    path = %path.display(),
+                    "read bearer tokens"
+                );
+                Some(TokenFile {
+                    path: path.clone(),
+                    loaded: Mutex::new(loaded),
+                })
+            }
+            None => None,
+        };
+
+        Ok(Auth {
+            users: config.users.clone(),
+            endpoints: config.endpoints.clone(),
+            tokens,
+        })
+    }
+
+    /// Whether the endpoint wants credentials at all
+    pub fn protects(&self, endpoint: &str) -> bool {
+        self.endpoints.contains_key(endpoint)
+    }
+
+    /// Lets the request through if the endpoint is open or the credentials
+    /// are good, otherwise hands back the 401 to answer with
+    pub fn check(&self, endpoint: &str, request: &Request) -> Result<(), Response> {
+        let Some(schemes) = self.endpoints.get(endpoint) else {
+            return Ok(());
+        };
+
+        let header = request.header("Authorization").unwrap_or_default();
+        let (scheme, credentials) = header.split_once(' ').unwrap_or((header, ""));
+        let credentials = credentials.trim();
+
+        let mut invalid_token = false;
+        if scheme.eq_ignore_ascii_case("Basic") && schemes.contains(&AuthScheme::Basic) {
+            if self.basic_ok(credentials) {
+                return Ok(());
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") && schemes.contains(&AuthScheme::Bearer) {
+            if self.bearer_ok(credentials) {
+                return Ok(());
+            }
+            invalid_token = true;
+        }
+
+        // one challenge per scheme the endpoint takes, in the configured order
+        let mut response = Response::new(401);
+        for scheme in schemes {
+            let challenge = match scheme {
+                AuthScheme::Basic => format!("Basic realm=\"{REALM}\", charset=\"UTF-8\""),
+                AuthScheme::Bearer if invalid_token => {
+                    format!("Bearer realm=\"{REALM}\", error=\"invalid_token\"")
+                }
+                AuthScheme::Bearer => format!("Bearer realm=\"{REALM}\""),
+            };
+            response = response.with_header("WWW-Authenticate", &challenge);
+        }
+        Err(response)
+    }
+
+    fn basic_ok(&self, credentials: &str) -> bool {
+        let Ok(decoded) = STANDARD.decode(credentials) else {
+            return false;
+        };
+        let Ok(decoded) = String::from_utf8(decoded) else {
+            return false;
+        };
+        let Some((user, password)) = decoded.split_once(':') else {
+            return false;
+        };
+        self.users
+            .get(user)
+            .is_some_and(|expected| same(expected.as_bytes(), password.as_bytes()))
+    }
+
+    fn bearer_ok(&self, token: &str) -> bool {
+        self.tokens
+            .as_ref()
+            .is_some_and(|tokens| !token.is_empty() && tokens.accepts(token))
+    }
+}
+
+impl TokenFile {
+    fn accepts(&self, token: &str) -> bool {
+        let mut loaded = self.loaded.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified != loaded.modified {
+            match read_tokens(&self.path) {
+                Ok(fresh) => {
+                    info!(
+                        tokens = fresh.tokens.len(),
+                        path = %self.path.display(),
+                        "reloaded bearer tokens"
+                    );
+                    *loaded = fresh;
+                }
+                // a file halfway through being replaced shouldn't lock everyone out
+                Err(err) => {
+                    warn!(
+                        path = %self.path.display(),
+                        "could not reload bearer tokens, keeping the old ones: {err}"
+                    );
+                    loaded.modified = modified;
+                }
+            }
+        }
+
+        loaded
+            .tokens
+            .iter()
+            .any(|accepted| same(accepted.as_bytes(), token.as_bytes()))
+    }
+}
+
+// one token per line, blank lines and # comments left out
+fn read_tokens(path: &Path) -> io::Result<Tokens> {
+    let modified = fs::metadata(path)?.modified().ok();
+    let tokens = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    Ok(Tokens { modified, tokens })
+}
+
+// looks at every byte rather than stopping at the first difference, so the
+// response time doesn't tell how much of a secret was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Version;
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn request(authorization: Option<&str>) -> Request {
+        Request {
+            method: "GET".into(),
+            path: "/metrics".into(),
+            query: Vec::new(),
+            version: Version::Http11,
+            headers: authorization
+                .map(|value| ("Authorization".into(), value.into()))
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    fn auth(schemes: &[AuthScheme], token_file: Option<&Path>) -> Auth {
+        Auth::new(&AuthConfig {
+            users: BTreeMap::from([("prometheus".into(), "secret".into())]),
+            bearer_token_file: token_file.map(Path::to_path_buf),
+            endpoints: BTreeMap::from([("metrics".into(), schemes.to_vec())]),
+        })
+        .unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    fn challenges(response: Response) -> Vec<String> {
+        assert_eq!(response.status, 401);
+        response
+            .headers
+            .into_iter()
+            .filter(|(name, _)| name == "WWW-Authenticate")
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    fn token_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("metrics_generator_{name}.tokens"));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn checks_basic_credentials() {
+        let auth = auth(&[AuthScheme::Basic], None);
+        assert!(auth.check("stats", &request(None)).is_ok());
+        assert!(auth
+            .check("metrics", &request(Some(&basic("prometheus:secret"))))
+            .is_ok());
+        // the scheme name is case-insensitive
+        let lowercase = basic("prometheus:secret").replacen("Basic", "basic", 1);
+        assert!(auth.check("metrics", &request(Some(&lowercase))).is_ok());
+
+        for header in [
+            None,
+            Some(basic("prometheus:wrong")),
+            Some(basic("grafana:secret")),
+            Some(basic("prometheus")),
+            Some("Basic !!!".into()),
+            Some("Bearer secret".into()),
+        ] {
+            let err = auth
+                .check("metrics", &request(header.as_deref()))
+                .unwrap_err();
+            assert_eq!(
+                challenges(err),
+                ["Basic realm=\"metrics_generator\", charset=\"UTF-8\""],
+                "{header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let path = token_file("bearer", "# scrapers\n\nfirst\n  second  \n");
+        let auth = auth(&[AuthScheme::Bearer], Some(&path));
+        assert!(auth
+            .check("metrics", &request(Some("Bearer first")))
+            .is_ok());
+        assert!(auth
+            .check("metrics", &request(Some("Bearer second")))
+            .is_ok());
+
+        let err = auth.check("metrics", &request(None)).unwrap_err();
+        assert_eq!(challenges(err), ["Bearer realm=\"metrics_generator\""]);
+        for header in ["Bearer third", "Bearer", "Bearer # scrapers"] {
+            let err = auth.check("metrics", &request(Some(header))).unwrap_err();
+            assert_eq!(
+                challenges(err),
+                ["Bearer realm=\"metrics_generator\", error=\"invalid_token\""],
+                "{header}"
+            );
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn challenges_with_every_scheme_in_order() {
+        let auth = auth(&[AuthScheme::Bearer, AuthScheme::Basic], None);
+        assert!(auth
+            .check("metrics", &request(Some(&basic("prometheus:secret"))))
+            .is_ok());
+        let err = auth.check("metrics", &request(None)).unwrap_err();
+        assert_eq!(
+            challenges(err),
+            [
+                "Bearer realm=\"metrics_generator\"",
+                "Basic realm=\"metrics_generator\", charset=\"UTF-8\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn rereads_the_token_file_when_it_changes() {
+        let path = token_file("reread", "old\n");
+        let auth = auth(&[AuthScheme::Bearer], Some(&path));
+        assert!(auth.check("metrics", &request(Some("Bearer old"))).is_ok());
+
+        // a later modification time than the first read, whatever the
+        // resolution of the file system's clock
+        fs::write(&path, "new\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+        assert!(auth.check("metrics", &request(Some("Bearer new"))).is_ok());
+        assert!(auth.check("metrics", &request(Some("Bearer old"))).is_err());
+
+        // a file gone missing keeps the tokens there were
+        fs::remove_file(&path).unwrap();
+        assert!(auth.check("metrics", &request(Some("Bearer new"))).is_ok());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::Config;
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+
+/// The content codings responses can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// what Prometheus asks for
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Picks a coding from an Accept-Encoding header by q-value, zstd when the
+/// client names both and likes them as much. `*` stands for the codings the
+/// header doesn't name (RFC 9110 12.5.3), gzip winning a tie there. None when
+/// it accepts neither, or sent no header.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let (mut gzip, mut zstd, mut any) = (None, None, None);
+    for coding in accept_encoding?.split(',') {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let quality: f64 = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(1.0, |(_, value)| value.trim().parse().unwrap_or(0.0));
+
+        let named = match name.as_str() {
+            "gzip" | "x-gzip" => &mut gzip,
+            "zstd" => &mut zstd,
+            "*" => &mut any,
+            _ => continue,
+        };
+        named.get_or_insert(quality);
+    }
+
+    let zstd_named = zstd.is_some();
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let zstd = zstd.or(any).unwrap_or(0.0);
+    if zstd > 0.0 && (zstd > gzip || (zstd == gzip && zstd_named)) {
+        Some(Encoding::Zstd)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compresses a response body with the level configured for the coding
+pub fn compress(encoding: Encoding, body: &[u8], config: &Config) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let level = flate2::Compression::new(config.gzip_level);
+            let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Zstd => zstd::encode_all(body, config.zstd_level),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_by_quality() {
+        assert_eq!(negotiate(None), None);
+        assert_eq!(negotiate(Some("gzip")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("gzip, zstd")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(Some("gzip, zstd;q=0.5")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("br, identity")), None);
+    }
+
+    #[test]
+    fn takes_a_refusal_over_the_wildcard() {
+        assert_eq!(negotiate(Some("gzip;q=0, *")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(Some("gzip;q=0, zstd;q=0, *")), None);
+        assert_eq!(negotiate(Some("*;q=0")), None);
+        assert_eq!(negotiate(Some("*;q=0, gzip")), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn fills_in_unnamed_codings_from_the_wildcard() {
+        assert_eq!(negotiate(Some("*")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("gzip;q=0.5, *")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(Some("zstd;q=0.5, *")), Some(Encoding::Gzip));
+    }
+}
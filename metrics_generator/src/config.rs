@@ -0,0 +1,1250 @@
+use crate::telemetry;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+/// profile name of hosts built from [profile]
+pub const DEFAULT_PROFILE: &str = "default";
+// every host is ticked in this process and scraped along with the others
+// from /metrics, past this many that takes longer than a tick
+const MAX_HOST_COUNT: usize = 1000;
+
+// precedence, lowest to highest: defaults, config file, environment, CLI flags
+// clap takes care of the last two, since every flag falls back to its env var
+
+/// Simulated server that exposes randomised host metrics for Prometheus demos
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file to read settings from
+    #[arg(short, long, env = "METRICS_GEN_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// port to listen on
+    #[arg(short, long, env = "METRICS_GEN_PORT")]
+    pub port: Option<u16>,
+
+    /// address to listen on, repeat for more than one, e.g. 0.0.0.0 and ::
+    #[arg(long, env = "METRICS_GEN_LISTEN_ADDRESS", value_delimiter = ',')]
+    pub listen_address: Vec<IpAddr>,
+
+    /// also serve the fleet on a Unix domain socket at this path
+    #[arg(long, env = "METRICS_GEN_UNIX_SOCKET_PATH")]
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// maximum number of connections served at the same time
+    #[arg(long, env = "METRICS_GEN_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// how long to wait for a client to send its request, in milliseconds
+    #[arg(long, env = "METRICS_GEN_READ_TIMEOUT_MS")]
+    pub read_timeout_ms: Option<u64>,
+
+    /// how long to wait for a client to accept the response, in milliseconds
+    #[arg(long, env = "METRICS_GEN_WRITE_TIMEOUT_MS")]
+    pub write_timeout_ms: Option<u64>,
+
+    /// how long a kept alive connection may wait for its next request before
+    /// its worker is let go, in milliseconds
+    #[arg(long, env = "METRICS_GEN_IDLE_TIMEOUT_MS")]
+    pub idle_timeout_ms: Option<u64>,
+
+    /// how long to wait for open connections on shutdown, in milliseconds
+    #[arg(long, env = "METRICS_GEN_DRAIN_TIMEOUT_MS")]
+    pub drain_timeout_ms: Option<u64>,
+
+    /// what to log, a level such as debug or per target filters like
+    /// info,access=off
+    #[arg(long, env = "METRICS_GEN_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// write log lines as plain text or as one JSON object each
+    #[arg(long, env = "METRICS_GEN_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// where the values come from: made up, played back from --replay-path,
+    /// or this machine's CPU and memory
+    #[arg(long, env = "METRICS_GEN_SOURCE")]
+    pub source: Option<Source>,
+
+    /// JSON lines of recorded /stats bodies for --source replay
+    #[arg(long, env = "METRICS_GEN_REPLAY_PATH")]
+    pub replay_path: Option<PathBuf>,
+
+    /// seed for the random values, with --time-step-ms the same seed gives the
+    /// same output tick for tick
+    #[arg(long, env = "METRICS_GEN_SEED")]
+    pub seed: Option<u64>,
+
+    /// how often the simulation moves on, in milliseconds. Every endpoint
+    /// reports the state of the last tick
+    #[arg(long, env = "METRICS_GEN_TICK_INTERVAL_MS")]
+    pub tick_interval_ms: Option<u64>,
+
+    /// advance simulated time by this many milliseconds per tick instead of
+    /// following the wall clock, combine with --seed for reproducible runs
+    #[arg(long, env = "METRICS_GEN_TIME_STEP_MS")]
+    pub time_step_ms: Option<u64>,
+
+    /// TOML file with a timeline of phases for the simulated server to follow
+    #[arg(long, env = "METRICS_GEN_SCENARIO")]
+    pub scenario: Option<PathBuf>,
+
+    /// number of identical hosts to simulate, up to 1000, when the config
+    /// file doesn't list them with [[host]]
+    #[arg(long, env = "METRICS_GEN_HOST_COUNT")]
+    pub host_count: Option<usize>,
+
+    /// give every host a listener of its own, on consecutive ports from this one
+    #[arg(long, env = "METRICS_GEN_HOST_PORT_START")]
+    pub host_port_start: Option<u16>,
+
+    /// write a Prometheus file_sd target list of the per host listeners here
+    #[arg(long, env = "METRICS_GEN_FILE_SD_PATH")]
+    pub file_sd_path: Option<PathBuf>,
+
+    /// prefix for the metrics exposed on /metrics
+    #[arg(long, env = "METRICS_GEN_NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// PEM certificate chain to serve TLS with, needs --tls-key-path
+    #[arg(long, env = "METRICS_GEN_TLS_CERT_PATH")]
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "METRICS_GEN_TLS_KEY_PATH")]
+    pub tls_key_path: Option<PathBuf>,
+
+    /// serve TLS with a certificate generated at startup, written to the cert
+    /// and key paths when given
+    #[arg(long, env = "METRICS_GEN_TLS_SELF_SIGNED")]
+    pub tls_self_signed: bool,
+
+    /// PEM CA certificates that clients must present a certificate from
+    #[arg(long, env = "METRICS_GEN_TLS_CLIENT_CA_PATH")]
+    pub tls_client_ca_path: Option<PathBuf>,
+
+    /// gzip level for compressed responses, 1 (fastest) to 9 (smallest)
+    #[arg(long, env = "METRICS_GEN_GZIP_LEVEL")]
+    pub gzip_level: Option<u32>,
+
+    /// zstd level for compressed responses, 1 (fastest) to 22 (smallest)
+    #[arg(long, env = "METRICS_GEN_ZSTD_LEVEL")]
+    pub zstd_level: Option<i32>,
+
+    /// responses smaller than this many bytes are sent uncompressed
+    #[arg(long, env = "METRICS_GEN_COMPRESSION_MIN_BYTES")]
+    pub compression_min_bytes: Option<usize>,
+
+    /// number of CPU cores of the simulated server
+    #[arg(long, env = "METRICS_GEN_CORE_COUNT")]
+    pub core_count: Option<u32>,
+
+    /// total memory of the simulated server in bytes
+    #[arg(long, env = "METRICS_GEN_TOTAL_BYTES")]
+    pub total_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    /// the port and every host port are listened on at each of these
+    pub listen_addresses: Vec<IpAddr>,
+    pub unix_socket_path: Option<PathBuf>,
+    pub max_connections: usize,
+    pub read_timeout_ms: u64,
+    pub write_timeout_ms: u64,
+    /// between the requests of a kept alive connection, reread on reload
+    pub idle_timeout_ms: u64,
+    /// how long a shutdown waits for requests in flight
+    pub drain_timeout_ms: u64,
+    /// tracing filter directives, reread on reload
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub source: Source,
+    /// what the replay source plays back
+    pub replay_path: Option<PathBuf>,
+    pub seed: Option<u64>,
+    /// how often the hosts take a new snapshot, reread on reload
+    pub tick_interval_ms: u64,
+    pub time_step_ms: Option<u64>,
+    pub scenario: Option<PathBuf>,
+    pub namespace: String,
+    // the default shape of a simulated host
+    pub profile: Profile,
+    /// named shapes hosts can pick with `profile = "<name>"`
+    pub profiles: BTreeMap<String, Profile>,
+    pub host_count: Option<usize>,
+    pub host_port_start: Option<u16>,
+    pub file_sd_path: Option<PathBuf>,
+    /// upper bounds of the request latency histogram buckets, in seconds
+    pub latency_buckets: Vec<f64>,
+    /// resolution of the native histograms, from -4 (coarsest, each bucket
+    /// 16 times the last) to 8 (finest)
+    pub native_histogram_schema: i32,
+    /// certificate and key to serve TLS with, or where to write the
+    /// self-signed ones
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub tls_self_signed: bool,
+    /// CAs to verify client certificates against, for mutual TLS
+    pub tls_client_ca_path: Option<PathBuf>,
+    /// levels for responses compressed on request of Accept-Encoding
+    pub gzip_level: u32,
+    pub zstd_level: i32,
+    /// below this size compressing costs more than it saves
+    pub compression_min_bytes: usize,
+    pub auth: AuthConfig,
+    #[serde(rename = "host")]
+    pub hosts: Vec<HostConfig>,
+}
+
+// the shape of a simulated box
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub core_count: u32,
+    pub total_bytes: u64,
+    /// chance of a health check failing
+    pub unhealthy_chance: f64,
+    #[serde(rename = "disk")]
+    pub disks: Vec<Disk>,
+    #[serde(rename = "interface")]
+    pub interfaces: Vec<Interface>,
+    #[serde(rename = "endpoint")]
+    pub endpoints: Vec<Endpoint>,
+    /// how often the box reboots on average, which starts all its counters
+    /// over
+    pub restarts_per_day: f64,
+}
+
+/// A network interface of a simulated box, with its usual traffic
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Interface {
+    pub device: String,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+}
+
+/// An endpoint the simulated box serves requests on
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub path: String,
+    pub requests_per_sec: f64,
+    /// latency of a typical request on an idle box
+    pub median_latency_ms: f64,
+}
+
+/// A mounted filesystem of a simulated box
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Disk {
+    pub mount: String,
+    pub total_bytes: u64,
+    /// defaults to one per 16KiB, like mkfs.ext4 does
+    pub total_inodes: Option<u64>,
+    /// share of the disk in use at start
+    #[serde(default = "default_start_used")]
+    pub start_used: f64,
+    /// how fast the disk fills up, e.g. from logs or a growing database
+    #[serde(default)]
+    pub fill_bytes_per_sec: u64,
+    /// share of the disk at which it gets cleaned up back to `start_used`,
+    /// a disk without one fills up and stays full
+    pub cleanup_at: Option<f64>,
+}
+
+fn default_start_used() -> f64 {
+    0.5
+}
+
+/// A simulated host in the fleet, anything left out is taken from its named
+/// profile, or [profile] without one
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub id: String,
+    /// one of [profiles.<name>]
+    pub profile: Option<String>,
+    pub core_count: Option<u32>,
+    pub total_bytes: Option<u64>,
+    pub unhealthy_chance: Option<f64>,
+    #[serde(rename = "disk")]
+    pub disks: Option<Vec<Disk>>,
+    #[serde(rename = "interface")]
+    pub interfaces: Option<Vec<Interface>>,
+    #[serde(rename = "endpoint")]
+    pub endpoints: Option<Vec<Endpoint>>,
+    pub restarts_per_day: Option<f64>,
+    /// scenario for this host alone, instead of the top level one
+    pub scenario: Option<PathBuf>,
+    // only passed on to service discovery
+    pub datacenter: Option<String>,
+    pub role: Option<String>,
+}
+
+/// The body of `PUT /hosts/<id>`, what a [[host]] entry takes less anything
+/// that names a file on the generator's machine
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HostRequest {
+    pub profile: Option<String>,
+    pub core_count: Option<u32>,
+    pub total_bytes: Option<u64>,
+    pub unhealthy_chance: Option<f64>,
+    #[serde(rename = "disk")]
+    pub disks: Option<Vec<Disk>>,
+    #[serde(rename = "interface")]
+    pub interfaces: Option<Vec<Interface>>,
+    #[serde(rename = "endpoint")]
+    pub endpoints: Option<Vec<Endpoint>>,
+    pub restarts_per_day: Option<f64>,
+    pub datacenter: Option<String>,
+    pub role: Option<String>,
+}
+
+impl HostRequest {
+    /// The [[host]] entry it amounts to, following the top level scenario
+    pub fn host_config(self, id: &str) -> HostConfig {
+        HostConfig {
+            id: id.to_string(),
+            profile: self.profile,
+            core_count: self.core_count,
+            total_bytes: self.total_bytes,
+            unhealthy_chance: self.unhealthy_chance,
+            disks: self.disks,
+            interfaces: self.interfaces,
+            endpoints: self.endpoints,
+            restarts_per_day: self.restarts_per_day,
+            scenario: None,
+            datacenter: self.datacenter,
+            role: self.role,
+        }
+    }
+}
+
+/// A host with all of its settings worked out
+#[derive(Debug, Clone)]
+pub struct HostSpec {
+    pub id: String,
+    /// name of the profile the host was built from, "default" for [profile]
+    pub profile_name: String,
+    pub profile: Profile,
+    pub scenario: Option<PathBuf>,
+    /// port of the host's own listener, if hosts get one
+    pub port: Option<u16>,
+    pub datacenter: Option<String>,
+    pub role: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 8443,
+            listen_addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            unix_socket_path: None,
+            max_connections: 16,
+            read_timeout_ms: 5000,
+            write_timeout_ms: 5000,
+            // a scraper coming back every 15 seconds reconnects rather than
+            // holding on to a worker in between
+            idle_timeout_ms: 1000,
+            // enough for the read timeout of a connection kept alive
+            drain_timeout_ms: 10000,
+            // startup, shutdown and one access line per request
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            source: Source::Random,
+            replay_path: None,
+            seed: None,
+            // several snapshots per scrape interval, without busying a core
+            tick_interval_ms: 1000,
+            time_step_ms: None,
+            scenario: None,
+            namespace: "my_server_instr".to_string(),
+            profile: Profile::default(),
+            profiles: BTreeMap::new(),
+            host_count: None,
+            host_port_start: None,
+            file_sd_path: None,
+            // the Prometheus client defaults
+            latency_buckets: vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+            // buckets about 9% apart
+            native_histogram_schema: 3,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_self_signed: false,
+            tls_client_ca_path: None,
+            // the usual defaults of both
+            gzip_level: 6,
+            zstd_level: 3,
+            // about a TCP segment
+            compression_min_bytes: 1024,
+            // everything open, as before there was auth
+            auth: AuthConfig::default(),
+            hosts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// for log shippers, with the fields of a line as keys
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// simulated from the profile, and the scenario if there is one
+    Random,
+    /// recorded snapshots from `replay_path`, one per tick
+    Replay,
+    /// read from /proc, the profile's core_count and total_bytes taken from
+    /// the machine as well
+    Host,
+}
+
+/// Who may read what, nothing needs credentials unless listed in `endpoints`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// user names with their passwords, for basic auth
+    pub users: BTreeMap<String, String>,
+    /// one accepted bearer token per line, reread whenever the file changes
+    pub bearer_token_file: Option<PathBuf>,
+    /// the endpoints that want credentials, by the first segment of their
+    /// path, with the schemes they take
+    pub endpoints: BTreeMap<String, Vec<AuthScheme>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+}
+
+/// Endpoints that can be put behind auth, /hosts/<id>/metrics counting as
+/// metrics and so on
+pub const AUTH_ENDPOINTS: [&str; 5] = ["metrics", "stats", "healthz", "sd", "hosts"];
+
+impl AuthConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (endpoint, schemes) in &self.endpoints {
+            if !AUTH_ENDPOINTS.contains(&endpoint.as_str()) {
+                return Err(format!(
+                    "unknown endpoint {endpoint:?}, expected one of {}",
+                    AUTH_ENDPOINTS.join(", ")
+                ));
+            }
+            if schemes.is_empty() {
+                return Err(format!("endpoint {endpoint:?} needs at least one scheme"));
+            }
+            if schemes.contains(&AuthScheme::Basic) && self.users.is_empty() {
+                return Err(format!(
+                    "endpoint {endpoint:?} takes basic auth but there are no users"
+                ));
+            }
+            if schemes.contains(&AuthScheme::Bearer) && self.bearer_token_file.is_none() {
+                return Err(format!(
+                    "endpoint {endpoint:?} takes bearer tokens but there is no bearer_token_file"
+                ));
+            }
+        }
+
+        // the colon separates user and password in the header
+        if let Some(user) = self
+            .users
+            .keys()
+            .find(|user| user.is_empty() || user.contains(':'))
+        {
+            return Err(format!("invalid user name {user:?}"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            core_count: 8,
+            total_bytes: 4294967296, // 4GB
+            unhealthy_chance: 0.1,
+            disks: vec![
+                // the system disk, where the logs get rotated well before it
+                // runs out
+                Disk {
+                    mount: "/".to_string(),
+                    total_bytes: 53687091200, // 50GB
+                    total_inodes: None,
+                    start_used: 0.35,
+                    fill_bytes_per_sec: 20_000,
+                    cleanup_at: Some(0.8),
+                },
+                // a data disk that fills up in about two hours and is only
+                // cleaned up once it is completely full
+                Disk {
+                    mount: "/data".to_string(),
+                    total_bytes: 536870912000, // 500GB
+                    total_inodes: None,
+                    start_used: 0.6,
+                    fill_bytes_per_sec: 30_000_000,
+                    cleanup_at: Some(1.0),
+                },
+            ],
+            interfaces: vec![
+                Interface {
+                    device: "eth0".to_string(),
+                    rx_bytes_per_sec: 5_000_000,
+                    tx_bytes_per_sec: 2_000_000,
+                },
+                Interface {
+                    device: "lo".to_string(),
+                    rx_bytes_per_sec: 200_000,
+                    tx_bytes_per_sec: 200_000,
+                },
+            ],
+            endpoints: vec![
+                Endpoint {
+                    path: "/api/orders".to_string(),
+                    requests_per_sec: 50.0,
+                    median_latency_ms: 25.0,
+                },
+                Endpoint {
+                    path: "/api/search".to_string(),
+                    requests_per_sec: 20.0,
+                    median_latency_ms: 80.0,
+                },
+                Endpoint {
+                    path: "/login".to_string(),
+                    requests_per_sec: 5.0,
+                    median_latency_ms: 150.0,
+                },
+            ],
+            restarts_per_day: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+    /// the certificates or keys to serve TLS with are unusable
+    Tls(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => {
+                write!(f, "could not read {}: {err}", path.display())
+            }
+            ConfigError::Parse(path, err) => {
+                write!(f, "could not parse {}: {err}", path.display())
+            }
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {msg}"),
+            ConfigError::Tls(msg) => write!(f, "TLS setup failed: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the effective config from the parsed command line (and env vars)
+    pub fn load(cli: &Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if !cli.listen_address.is_empty() {
+            config.listen_addresses = cli.listen_address.clone();
+        }
+        if cli.unix_socket_path.is_some() {
+            config.unix_socket_path = cli.unix_socket_path.clone();
+        }
+        if let Some(max_connections) = cli.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(read_timeout_ms) = cli.read_timeout_ms {
+            config.read_timeout_ms = read_timeout_ms;
+        }
+        if let Some(write_timeout_ms) = cli.write_timeout_ms {
+            config.write_timeout_ms = write_timeout_ms;
+        }
+        if let Some(idle_timeout_ms) = cli.idle_timeout_ms {
+            config.idle_timeout_ms = idle_timeout_ms;
+        }
+        if let Some(drain_timeout_ms) = cli.drain_timeout_ms {
+            config.drain_timeout_ms = drain_timeout_ms;
+        }
+        if let Some(log_level) = &cli.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(log_format) = cli.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(source) = cli.source {
+            config.source = source;
+        }
+        if cli.replay_path.is_some() {
+            config.replay_path = cli.replay_path.clone();
+        }
+        if cli.seed.is_some() {
+            config.seed = cli.seed;
+        }
+        if let Some(tick_interval_ms) = cli.tick_interval_ms {
+            config.tick_interval_ms = tick_interval_ms;
+        }
+        if cli.time_step_ms.is_some() {
+            config.time_step_ms = cli.time_step_ms;
+        }
+        if cli.scenario.is_some() {
+            config.scenario = cli.scenario.clone();
+        }
+        if cli.host_count.is_some() {
+            config.host_count = cli.host_count;
+        }
+        if cli.host_port_start.is_some() {
+            config.host_port_start = cli.host_port_start;
+        }
+        if cli.file_sd_path.is_some() {
+            config.file_sd_path = cli.file_sd_path.clone();
+        }
+        if let Some(namespace) = &cli.namespace {
+            config.namespace = namespace.clone();
+        }
+        if cli.tls_cert_path.is_some() {
+            config.tls_cert_path = cli.tls_cert_path.clone();
+        }
+        if cli.tls_key_path.is_some() {
+            config.tls_key_path = cli.tls_key_path.clone();
+        }
+        if cli.tls_self_signed {
+            config.tls_self_signed = true;
+        }
+        if cli.tls_client_ca_path.is_some() {
+            config.tls_client_ca_path = cli.tls_client_ca_path.clone();
+        }
+        if let Some(gzip_level) = cli.gzip_level {
+            config.gzip_level = gzip_level;
+        }
+        if let Some(zstd_level) = cli.zstd_level {
+            config.zstd_level = zstd_level;
+        }
+        if let Some(compression_min_bytes) = cli.compression_min_bytes {
+            config.compression_min_bytes = compression_min_bytes;
+        }
+        if let Some(core_count) = cli.core_count {
+            config.profile.core_count = core_count;
+        }
+        if let Some(total_bytes) = cli.total_bytes {
+            config.profile.total_bytes = total_bytes;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Takes over what only a restart can change from the running config,
+    /// returns the names of the settings that differed
+    pub fn keep_restart_only(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != running.$field {
+                        kept.push(stringify!($field));
+                        self.$field = running.$field.clone();
+                    }
+                )*
+            };
+        }
+        // the listeners, and what the simulation was set up with
+        keep!(
+            port,
+            listen_addresses,
+            unix_socket_path,
+            max_connections,
+            log_format,
+            source,
+            replay_path,
+            seed,
+            time_step_ms,
+            scenario,
+            namespace,
+            host_count,
+            host_port_start,
+            file_sd_path,
+            latency_buckets,
+            native_histogram_schema,
+            // hosts already running keep their shape, ones added later on
+            // would take the new one
+            profile,
+            profiles,
+            hosts
+        );
+        kept
+    }
+
+    /// Workers to serve with: `max_connections` for the main listeners and
+    /// two for each host with a listener of its own, so a scraper per host
+    /// target never finds them all taken
+    pub fn workers(&self, host_listeners: usize) -> usize {
+        self.max_connections + 2 * host_listeners
+    }
+
+    /// The address service discovery hands out for the listeners, the first
+    /// one listened on, or loopback when that is any address
+    pub fn target_ip(&self) -> IpAddr {
+        match self.listen_addresses.first() {
+            Some(IpAddr::V4(ip)) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Some(IpAddr::V6(ip)) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            Some(ip) => *ip,
+            None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+
+    /// Whether the listeners speak HTTPS
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_self_signed || self.tls_cert_path.is_some()
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.clone(), e))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::Invalid(
+                "port must be between 1 and 65535".into(),
+            ));
+        }
+
+        if self.listen_addresses.is_empty() {
+            return Err(ConfigError::Invalid(
+                "listen_addresses must list at least one address".into(),
+            ));
+        }
+        let unique: HashSet<_> = self.listen_addresses.iter().collect();
+        if unique.len() != self.listen_addresses.len() {
+            return Err(ConfigError::Invalid(
+                "listen_addresses must not list an address twice".into(),
+            ));
+        }
+
+        if self.max_connections == 0 {
+            return Err(ConfigError::Invalid(
+                "max_connections must be at least 1".into(),
+            ));
+        }
+
+        // a zero duration socket timeout is rejected by std, and would mean
+        // waiting forever on a stalled client anyway
+        if self.read_timeout_ms == 0 || self.write_timeout_ms == 0 || self.idle_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "read_timeout_ms, write_timeout_ms and idle_timeout_ms must be at least 1".into(),
+            ));
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.log_level) {
+            return Err(ConfigError::Invalid(format!(
+                "log_level {:?} is not a level or filter: {err}",
+                self.log_level
+            )));
+        }
+
+        if self.source == Source::Replay && self.replay_path.is_none() {
+            return Err(ConfigError::Invalid(
+                "source \"replay\" needs replay_path to play back".into(),
+            ));
+        }
+
+        if self.tick_interval_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "tick_interval_ms must be at least 1".into(),
+            ));
+        }
+
+        if self.time_step_ms == Some(0) {
+            return Err(ConfigError::Invalid(
+                "time_step_ms must be at least 1 when set".into(),
+            ));
+        }
+
+        let buckets = &self.latency_buckets;
+        if buckets.is_empty()
+            || buckets
+                .iter()
+                .any(|bound| !(*bound > 0.0 && bound.is_finite()))
+            || buckets.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return Err(ConfigError::Invalid(
+                "latency_buckets must be positive, finite and strictly increasing".into(),
+            ));
+        }
+
+        if !(-4..=8).contains(&self.native_histogram_schema) {
+            return Err(ConfigError::Invalid(
+                "native_histogram_schema must be between -4 and 8".into(),
+            ));
+        }
+
+        // a self-signed certificate may be written to both paths, or neither
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err(ConfigError::Invalid(
+                "tls_cert_path and tls_key_path must be set together".into(),
+            ));
+        }
+
+        if self.tls_client_ca_path.is_some() && !self.tls_enabled() {
+            return Err(ConfigError::Invalid(
+                "tls_client_ca_path needs TLS, set tls_cert_path or tls_self_signed".into(),
+            ));
+        }
+
+        if !(1..=9).contains(&self.gzip_level) {
+            return Err(ConfigError::Invalid(
+                "gzip_level must be between 1 and 9".into(),
+            ));
+        }
+
+        if !(1..=22).contains(&self.zstd_level) {
+            return Err(ConfigError::Invalid(
+                "zstd_level must be between 1 and 22".into(),
+            ));
+        }
+
+        if !is_valid_metric_name(&self.namespace) {
+            return Err(ConfigError::Invalid(format!(
+                "namespace {:?} is not a valid prometheus metric name prefix, \
+                 use letters, digits, '_' and ':' only and do not start with a digit",
+                self.namespace
+            )));
+        }
+
+        if self.namespace == telemetry::NAMESPACE {
+            return Err(ConfigError::Invalid(format!(
+                "namespace {:?} is taken by the generator's own metrics",
+                self.namespace
+            )));
+        }
+
+        if self.host_count.is_some() && !self.hosts.is_empty() {
+            return Err(ConfigError::Invalid(
+                "set either host_count or a list of [[host]], not both".into(),
+            ));
+        }
+        if self.host_count == Some(0) {
+            return Err(ConfigError::Invalid("host_count must be at least 1".into()));
+        }
+        if self.host_count.is_some_and(|count| count > MAX_HOST_COUNT) {
+            return Err(ConfigError::Invalid(format!(
+                "host_count must be at most {MAX_HOST_COUNT}, run more generators for a bigger fleet"
+            )));
+        }
+
+        self.auth
+            .validate()
+            .map_err(|err| ConfigError::Invalid(format!("auth: {err}")))?;
+
+        // hosts left to [profile], host_count ones included, are only ever
+        // checked here
+        self.profile
+            .validate()
+            .map_err(|err| ConfigError::Invalid(format!("profile: {err}")))?;
+        for (name, profile) in &self.profiles {
+            profile
+                .validate()
+                .map_err(|err| ConfigError::Invalid(format!("profile {name:?}: {err}")))?;
+        }
+
+        let hosts = self.hosts()?;
+        if let Some(start) = self.host_port_start {
+            let end = start as usize + hosts.len() - 1;
+            if start == 0 || end > u16::MAX as usize {
+                return Err(ConfigError::Invalid(format!(
+                    "host ports {start}-{end} do not fit in 1-65535"
+                )));
+            }
+            if (start as usize..=end).contains(&(self.port as usize)) {
+                return Err(ConfigError::Invalid(format!(
+                    "host ports {start}-{end} overlap with port {}",
+                    self.port
+                )));
+            }
+        } else if self.file_sd_path.is_some() {
+            return Err(ConfigError::Invalid(
+                "file_sd_path lists the per host listeners, set host_port_start too".into(),
+            ));
+        }
+
+        let mut ids = HashSet::new();
+        for host in hosts {
+            if !ids.insert(host.id.clone()) {
+                return Err(ConfigError::Invalid(format!(
+                    "duplicate host id {:?}",
+                    host.id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The simulated hosts, either as listed or `host_count` copies of the
+    /// default profile
+    pub fn hosts(&self) -> Result<Vec<HostSpec>, ConfigError> {
+        // ports are handed out in order, checked to fit by validate()
+        let port = |index: usize| {
+            self.host_port_start
+                .map(|start| (start as usize + index) as u16)
+        };
+
+        if self.hosts.is_empty() {
+            return Ok((0..self.host_count.unwrap_or(1))
+                .map(|index| HostSpec {
+                    id: format!("host-{}", index + 1),
+                    profile_name: DEFAULT_PROFILE.to_string(),
+                    profile: self.profile.clone(),
+                    scenario: self.scenario.clone(),
+                    port: port(index),
+                    datacenter: None,
+                    role: None,
+                })
+                .collect());
+        }
+
+        self.hosts
+            .iter()
+            .enumerate()
+            .map(|(index, host)| self.host_spec(host, port(index)))
+            .collect()
+    }
+
+    /// Works out the settings of a single host, checking them along the way
+    pub fn host_spec(&self, host: &HostConfig, port: Option<u16>) -> Result<HostSpec, ConfigError> {
+        if !is_valid_host_id(&host.id) {
+            return Err(ConfigError::Invalid(format!(
+                "host id {:?} must be non empty and only use letters, digits, '-', '_' and '.'",
+                host.id
+            )));
+        }
+
+        let (profile_name, base) = match &host.profile {
+            Some(name) => match self.profiles.get(name) {
+                Some(profile) => (name.clone(), profile),
+                None => {
+                    return Err(ConfigError::Invalid(format!(
+                        "host {:?}: no profile named {name:?}",
+                        host.id
+                    )))
+                }
+            },
+            None => (DEFAULT_PROFILE.to_string(), &self.profile),
+        };
+
+        let profile = Profile {
+            core_count: host.core_count.unwrap_or(base.core_count),
+            total_bytes: host.total_bytes.unwrap_or(base.total_bytes),
+            unhealthy_chance: host.unhealthy_chance.unwrap_or(base.unhealthy_chance),
+            disks: host.disks.clone().unwrap_or_else(|| base.disks.clone()),
+            interfaces: host
+                .interfaces
+                .clone()
+                .unwrap_or_else(|| base.interfaces.clone()),
+            endpoints: host
+                .endpoints
+                .clone()
+                .unwrap_or_else(|| base.endpoints.clone()),
+            restarts_per_day: host.restarts_per_day.unwrap_or(base.restarts_per_day),
+        };
+        profile
+            .validate()
+            .map_err(|err| ConfigError::Invalid(format!("host {:?}: {err}", host.id)))?;
+
+        Ok(HostSpec {
+            id: host.id.clone(),
+            profile_name,
+            profile,
+            scenario: host.scenario.clone().or_else(|| self.scenario.clone()),
+            port,
+            datacenter: host.datacenter.clone(),
+            role: host.role.clone(),
+        })
+    }
+}
+
+impl Profile {
+    fn validate(&self) -> Result<(), String> {
+        // the load generator spikes up to twice the core count
+        if self.core_count == 0 || self.core_count > u32::MAX / 2 {
+            return Err(format!(
+                "core_count must be between 1 and {}, got {}",
+                u32::MAX / 2,
+                self.core_count
+            ));
+        }
+
+        // used memory is picked between half and all of total_bytes
+        if self.total_bytes < 2 {
+            return Err(format!(
+                "total_bytes must be at least 2, got {}",
+                self.total_bytes
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.unhealthy_chance) {
+            return Err(format!(
+                "unhealthy_chance must be between 0 and 1, got {}",
+                self.unhealthy_chance
+            ));
+        }
+
+        let mut mounts = HashSet::new();
+        for disk in &self.disks {
+            disk.validate()?;
+            if !mounts.insert(&disk.mount) {
+                return Err(format!("duplicate disk mount {:?}", disk.mount));
+            }
+        }
+
+        let mut devices = HashSet::new();
+        for interface in &self.interfaces {
+            if interface.device.is_empty() {
+                return Err("interface device names must not be empty".into());
+            }
+            if !devices.insert(&interface.device) {
+                return Err(format!("duplicate interface device {:?}", interface.device));
+            }
+        }
+
+        let mut paths = HashSet::new();
+        for endpoint in &self.endpoints {
+            endpoint.validate()?;
+            if !paths.insert(&endpoint.path) {
+                return Err(format!("duplicate endpoint path {:?}", endpoint.path));
+            }
+        }
+
+        if !(self.restarts_per_day >= 0.0 && self.restarts_per_day.is_finite()) {
+            return Err(format!(
+                "restarts_per_day must be 0 or more, got {}",
+                self.restarts_per_day
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Endpoint {
+    fn validate(&self) -> Result<(), String> {
+        // paths end up as label values, keep them plain
+        let path = &self.path;
+        if !path.starts_with('/')
+            || !path
+                .chars()
+                .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\')
+        {
+            return Err(format!(
+                "endpoint path {path:?} must start with '/' and only use printable \
+                 characters other than '\"' and '\\'"
+            ));
+        }
+        // every request is simulated one by one
+        if !(0.0..=10000.0).contains(&self.requests_per_sec) {
+            return Err(format!(
+                "endpoint {path:?}: requests_per_sec must be between 0 and 10000"
+            ));
+        }
+        if !(self.median_latency_ms > 0.0 && self.median_latency_ms.is_finite()) {
+            return Err(format!(
+                "endpoint {path:?}: median_latency_ms must be above 0"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Disk {
+    pub fn total_inodes(&self) -> u64 {
+        self.total_inodes.unwrap_or(self.total_bytes / 16384).max(1)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mount = &self.mount;
+        if !mount.starts_with('/') {
+            return Err(format!("disk mount {mount:?} must be an absolute path"));
+        }
+        if self.total_bytes == 0 || self.total_inodes == Some(0) {
+            return Err(format!(
+                "disk {mount:?}: total_bytes and total_inodes must be at least 1"
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.start_used) {
+            return Err(format!(
+                "disk {mount:?}: start_used must be between 0 and 1"
+            ));
+        }
+        if self
+            .cleanup_at
+            .is_some_and(|at| !(at > self.start_used && at <= 1.0))
+        {
+            return Err(format!(
+                "disk {mount:?}: cleanup_at must be above start_used and at most 1"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// ids end up in URL paths and label values
+fn is_valid_host_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("metrics_generator_{name}.toml"));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> Result<Config, ConfigError> {
+        let cli = Cli::try_parse_from(["metrics_generator"].iter().chain(args)).unwrap();
+        Config::load(&cli)
+    }
+
+    #[test]
+    fn layers_flags_over_env_over_file_over_defaults() {
+        let path = config_file("layers", "max_connections = 8\n");
+        let path = path.to_str().unwrap();
+        assert_eq!(load(&[]).unwrap().max_connections, 16);
+        assert_eq!(load(&["--config", path]).unwrap().max_connections, 8);
+
+        // no other test reads this variable
+        env::set_var("METRICS_GEN_MAX_CONNECTIONS", "32");
+        let from_env = load(&["--config", path]);
+        let from_flag = load(&["--config", path, "--max-connections", "64"]);
+        env::remove_var("METRICS_GEN_MAX_CONNECTIONS");
+        assert_eq!(from_env.unwrap().max_connections, 32);
+        assert_eq!(from_flag.unwrap().max_connections, 64);
+
+        // what the file sets and nothing above it does survives the layering
+        let path = config_file("layers_kept", "max_connections = 8\nport = 9200\n");
+        let config = load(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--max-connections",
+            "64",
+        ])
+        .unwrap();
+        assert_eq!((config.port, config.max_connections), (9200, 64));
+    }
+
+    #[test]
+    fn refuses_bad_settings_from_any_layer() {
+        let path = config_file("invalid", "read_timeout_ms = 0\n");
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)), "{err}");
+        assert!(err.to_string().contains("read_timeout_ms"), "{err}");
+
+        // flags are checked the same way as the file
+        let err = load(&["--port", "0"]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)), "{err}");
+
+        let path = config_file("unknown", "max_conections = 8\n");
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(..)), "{err}");
+    }
+
+    #[test]
+    fn checks_the_default_profile() {
+        for profile in [
+            Profile {
+                core_count: 0,
+                ..Profile::default()
+            },
+            Profile {
+                total_bytes: 0,
+                ..Profile::default()
+            },
+        ] {
+            let config = Config {
+                host_count: Some(3),
+                profile,
+                ..Config::default()
+            };
+            assert!(config.validate().is_err());
+        }
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn bounds_the_host_count() {
+        let count = |host_count| Config {
+            host_count: Some(host_count),
+            ..Config::default()
+        };
+        assert!(count(MAX_HOST_COUNT).validate().is_ok());
+        let err = count(MAX_HOST_COUNT + 1).validate().unwrap_err();
+        assert!(err.to_string().contains("at most 1000"), "{err}");
+        // refused before a host is built for every one of them
+        assert!(count(usize::MAX).validate().is_err());
+    }
+
+    #[test]
+    fn keeps_the_shape_of_the_hosts_on_reload() {
+        let running = Config::default();
+        let mut reloaded = Config {
+            profile: Profile {
+                core_count: 2,
+                ..Profile::default()
+            },
+            gzip_level: 1,
+            ..Config::default()
+        };
+        assert_eq!(reloaded.keep_restart_only(&running), ["profile"]);
+        assert_eq!(reloaded.profile, running.profile);
+        assert_eq!(reloaded.gzip_level, 1);
+    }
+}
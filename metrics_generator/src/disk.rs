@@ -0,0 +1,215 @@
+use crate::config::Disk;
+use crate::load::LOAD_FREQ;
+use rand::Rng;
+
+// I/O at an idle box, per second, and the average request sizes
+const IDLE_READS: f64 = 40.0;
+const IDLE_WRITES: f64 = 25.0;
+const READ_SIZE: f64 = 16384.0;
+const WRITE_SIZE: f64 = 32768.0;
+// service time of an idle SSD
+const BASE_LATENCY_SECS: f64 = 0.0005;
+// average file size, for the inodes that growth takes up
+const FILE_SIZE: f64 = 65536.0;
+
+/// One mount and the I/O done against it since the box came up
+pub struct Mount {
+    pub disk: Disk,
+    pub used_bytes: f64,
+    pub used_inodes: f64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub reads: u64,
+    pub writes: u64,
+    /// average time an I/O took over the last tick
+    pub latency_secs: f64,
+}
+
+/// Simulates the disks of a box: how full each of them is, and the I/O going
+/// to them, which picks up along with the CPU load.
+pub struct DiskModel {
+    mounts: Vec<Mount>,
+}
+
+impl DiskModel {
+    pub fn new(disks: &[Disk]) -> DiskModel {
+        let mounts = disks
+            .iter()
+            .map(|disk| {
+                let used_bytes = disk.total_bytes as f64 * disk.start_used;
+                Mount {
+                    disk: disk.clone(),
+                    used_bytes,
+                    used_inodes: initial_inodes(disk),
+                    read_bytes: 0,
+                    written_bytes: 0,
+                    reads: 0,
+                    writes: 0,
+                    latency_secs: BASE_LATENCY_SECS,
+                }
+            })
+            .collect();
+
+        DiskModel { mounts }
+    }
+
+    /// Moves the simulation forward by that many ticks at `load_share`.
+    /// Unlike the load averages, fill up has to keep up with long gaps, so
+    /// they are caught up in one go.
+    pub fn advance(&mut self, rng: &mut impl Rng, ticks: u64, load_share: f64) {
+        if ticks == 0 {
+            return;
+        }
+        let secs = ticks as f64 * LOAD_FREQ.as_secs_f64();
+        for mount in &mut self.mounts {
+            mount.tick(rng, secs, load_share);
+        }
+    }
+
+    /// Starts the I/O counters over, as a reboot would
+    pub fn reset(&mut self) {
+        for mount in &mut self.mounts {
+            mount.read_bytes = 0;
+            mount.written_bytes = 0;
+            mount.reads = 0;
+            mount.writes = 0;
+        }
+    }
+
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+}
+
+impl Mount {
+    fn tick(&mut self, rng: &mut impl Rng, secs: f64, load_share: f64) {
+        let disk = &self.disk;
+        let total = disk.total_bytes as f64;
+
+        // a busy box does more I/O, give or take a fifth
+        let busy = 0.5 + load_share;
+        let reads = (IDLE_READS * busy * rng.gen_range(0.8..1.2) * secs) as u64;
+        let writes = (IDLE_WRITES * busy * rng.gen_range(0.8..1.2) * secs) as u64;
+        self.reads += reads;
+        self.writes += writes;
+        self.read_bytes += (reads as f64 * READ_SIZE * rng.gen_range(0.5..1.5)) as u64;
+        self.written_bytes += (writes as f64 * WRITE_SIZE * rng.gen_range(0.5..1.5)) as u64;
+
+        // queueing pushes latency up with the load, and so does a nearly
+        // full filesystem hunting for free blocks
+        let share = self.used_bytes / total;
+        let full_penalty = if share > 0.95 { 3.0 } else { 1.0 };
+        self.latency_secs = BASE_LATENCY_SECS
+            * (1.0 + load_share * load_share)
+            * full_penalty
+            * rng.gen_range(0.8..1.2);
+
+        // cleaned up the tick after it got there, so a disk that fills up
+        // completely is seen full at least once
+        if disk.cleanup_at.is_some_and(|at| share >= at) {
+            self.used_bytes = total * disk.start_used;
+            self.used_inodes = initial_inodes(disk);
+            return;
+        }
+
+        let growth = disk.fill_bytes_per_sec as f64 * secs * rng.gen_range(0.5..1.5);
+        self.used_bytes = (self.used_bytes + growth).min(total);
+        self.used_inodes = (self.used_inodes + growth / FILE_SIZE).min(disk.total_inodes() as f64);
+    }
+}
+
+// files are mostly well above the 16KiB an inode is set aside for, so only a
+// fraction of the inodes go with the bytes in use
+fn initial_inodes(disk: &Disk) -> f64 {
+    disk.total_inodes() as f64 * disk.start_used * 0.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const GIB: u64 = 1 << 30;
+
+    fn disk(cleanup_at: Option<f64>) -> Disk {
+        Disk {
+            mount: "/var".into(),
+            total_bytes: 10 * GIB,
+            total_inodes: Some(1000),
+            start_used: 0.5,
+            // a 5 second tick fills up to 1.5% of the disk
+            fill_bytes_per_sec: 20 << 20,
+            cleanup_at,
+        }
+    }
+
+    #[test]
+    fn fills_up_to_capacity_and_stays_there() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut disks = DiskModel::new(&[disk(None)]);
+        let mut used = disks.mounts()[0].used_bytes;
+        for _ in 0..100 {
+            disks.advance(&mut rng, 1, 0.5);
+            let mount = &disks.mounts()[0];
+            assert!(mount.used_bytes >= used);
+            used = mount.used_bytes;
+        }
+        let mount = &disks.mounts()[0];
+        assert_eq!(mount.used_bytes, (10 * GIB) as f64);
+        assert_eq!(mount.used_inodes, 1000.0);
+
+        // a long gap is caught up in one go, and clamped all the same
+        let mut disks = DiskModel::new(&[disk(None)]);
+        disks.advance(&mut rng, 10_000, 0.5);
+        assert_eq!(disks.mounts()[0].used_bytes, (10 * GIB) as f64);
+    }
+
+    #[test]
+    fn cleans_up_once_seen_past_the_mark() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut disks = DiskModel::new(&[disk(Some(0.8))]);
+        let mut peak = 0.0_f64;
+        let mut cleanups = 0;
+        for _ in 0..200 {
+            let before = disks.mounts()[0].used_bytes / (10 * GIB) as f64;
+            disks.advance(&mut rng, 1, 0.5);
+            let after = disks.mounts()[0].used_bytes / (10 * GIB) as f64;
+            if after < before {
+                // only ever from past the mark, back to where it started
+                assert!(before >= 0.8, "{before}");
+                assert_eq!(after, 0.5);
+                cleanups += 1;
+            }
+            peak = peak.max(after);
+        }
+        assert!(cleanups >= 2, "{cleanups}");
+        // by no more than a tick of growth
+        assert!((0.8..0.8 + 0.015).contains(&peak), "{peak}");
+    }
+
+    #[test]
+    fn reset_starts_the_counters_over() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut disks = DiskModel::new(&[disk(None)]);
+        disks.advance(&mut rng, 3, 1.0);
+        let mount = &disks.mounts()[0];
+        assert!(mount.reads > 0 && mount.writes > 0);
+        assert!(mount.read_bytes > 0 && mount.written_bytes > 0);
+        let used = mount.used_bytes;
+
+        disks.reset();
+        let mount = &disks.mounts()[0];
+        assert_eq!(
+            [
+                mount.reads,
+                mount.writes,
+                mount.read_bytes,
+                mount.written_bytes
+            ],
+            [0; 4]
+        );
+        // a reboot doesn't free any space
+        assert_eq!(mount.used_bytes, used);
+    }
+}
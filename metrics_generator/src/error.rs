@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Everything that can go wrong while serving a single request
+#[derive(Debug)]
+pub enum Error {
+    /// reading from or writing to the client failed
+    Io(io::Error),
+    /// the request could not be understood
+    Parse(String),
+    /// the request line is longer than we are willing to read
+    UriTooLong,
+    /// the request headers are larger than we are willing to read
+    HeadersTooLarge,
+    /// the request body is larger than we are willing to read
+    BodyTooLarge,
+    /// the request uses an HTTP version other than 1.0 and 1.1
+    VersionNotSupported(String),
+    /// the request relies on something this server doesn't implement
+    NotImplemented(String),
+    /// the request contained bytes that are not valid UTF-8
+    Encoding(FromUtf8Error),
+    /// the metrics registry could not be rendered
+    Metrics(fmt::Error),
+    /// the stats payload could not be turned into JSON
+    Serialize(serde_json::Error),
+    /// the response body could not be compressed
+    Compression(io::Error),
+}
+
+impl Error {
+    /// The status code to answer with, if the connection is still usable
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Io(err) => match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Some(408),
+                // the client is gone, nobody to answer to
+                _ => None,
+            },
+            Error::Parse(_) | Error::Encoding(_) => Some(400),
+            Error::UriTooLong => Some(414),
+            Error::HeadersTooLarge => Some(431),
+            Error::BodyTooLarge => Some(413),
+            Error::VersionNotSupported(_) => Some(505),
+            Error::NotImplemented(_) => Some(501),
+            Error::Metrics(_) | Error::Serialize(_) | Error::Compression(_) => Some(500),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "connection error: {err}"),
+            Error::Parse(msg) => write!(f, "malformed request: {msg}"),
+            Error::UriTooLong => write!(f, "request line too long"),
+            Error::HeadersTooLarge => write!(f, "request headers too large"),
+            Error::BodyTooLarge => write!(f, "request body too large"),
+            Error::VersionNotSupported(version) => write!(f, "unsupported version {version}"),
+            Error::NotImplemented(what) => write!(f, "not implemented: {what}"),
+            Error::Encoding(err) => write!(f, "request is not valid UTF-8: {err}"),
+            Error::Metrics(err) => write!(f, "could not encode metrics: {err}"),
+            Error::Serialize(err) => write!(f, "could not serialize stats: {err}"),
+            Error::Compression(err) => write!(f, "could not compress response: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse(_)
+            | Error::UriTooLong
+            | Error::HeadersTooLarge
+            | Error::BodyTooLarge
+            | Error::VersionNotSupported(_)
+            | Error::NotImplemented(_) => None,
+            Error::Encoding(err) => Some(err),
+            Error::Metrics(err) => Some(err),
+            Error::Serialize(err) => Some(err),
+            Error::Compression(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(err: FromUtf8Error) -> Self {
+        Error::Encoding(err)
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(err: fmt::Error) -> Self {
+        Error::Metrics(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serialize(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
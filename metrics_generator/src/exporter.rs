@@ -0,0 +1,710 @@
+use crate::error::Result;
+use crate::family::SortedFamily;
+use crate::generator::MetricsNet;
+use crate::histogram::{MirroredHistogram, MirroredSummary, NativeBuckets, SummaryTotals};
+use crate::protobuf::{self, PROTOBUF_CONTENT_TYPE};
+use crate::snapshot::{Family, Kind, LabelPairs, Snapshot, Value};
+use crate::source::HostSnapshot;
+use crate::text::{self, TEXT_CONTENT_TYPE};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::{Metric, Registry};
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// what the Go client uses, every latency is well above it
+const ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+/// The exposition formats a scrape can be rendered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    OpenMetrics,
+    /// the Prometheus text format 0.0.4
+    Text,
+    /// the only one with room for native histograms
+    Protobuf,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+            Format::Text => TEXT_CONTENT_TYPE,
+            Format::Protobuf => PROTOBUF_CONTENT_TYPE,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::OpenMetrics => "openmetrics",
+            Format::Text => "text",
+            Format::Protobuf => "protobuf",
+        }
+    }
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct HostLabels {
+    host: String,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct CpuLabels {
+    host: String,
+    bucket: String,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct DiskLabels {
+    host: String,
+    mount: String,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct NetLabels {
+    host: String,
+    device: String,
+}
+
+// the interface counters, in the order of net_values(). Named after the node
+// exporter's, the encoder adds the _total
+const NET_COUNTERS: [(&str, &str); 8] = [
+    ("network_receive_bytes", "bytes received on the interface"),
+    ("network_transmit_bytes", "bytes sent on the interface"),
+    (
+        "network_receive_packets",
+        "packets received on the interface",
+    ),
+    ("network_transmit_packets", "packets sent on the interface"),
+    ("network_receive_errs", "receive errors on the interface"),
+    ("network_transmit_errs", "transmit errors on the interface"),
+    (
+        "network_receive_drop",
+        "received packets dropped on the interface",
+    ),
+    (
+        "network_transmit_drop",
+        "outgoing packets dropped on the interface",
+    ),
+];
+
+fn net_values(net: &MetricsNet) -> [u64; 8] {
+    [
+        net.rx_bytes,
+        net.tx_bytes,
+        net.rx_packets,
+        net.tx_packets,
+        net.rx_errors,
+        net.tx_errors,
+        net.rx_dropped,
+        net.tx_dropped,
+    ]
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct EndpointLabels {
+    host: String,
+    endpoint: String,
+}
+
+impl LabelPairs for HostLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("host", self.host.clone())]
+    }
+}
+
+impl LabelPairs for CpuLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("host", self.host.clone()), ("bucket", self.bucket.clone())]
+    }
+}
+
+impl LabelPairs for DiskLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("host", self.host.clone()), ("mount", self.mount.clone())]
+    }
+}
+
+impl LabelPairs for NetLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("host", self.host.clone()), ("device", self.device.clone())]
+    }
+}
+
+impl LabelPairs for EndpointLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("host", self.host.clone()),
+            ("endpoint", self.endpoint.clone()),
+        ]
+    }
+}
+
+/// A registered family as the encoders other than the library's see it
+pub struct Described {
+    name: String,
+    /// with the full stop the registry adds
+    help: String,
+    family: Box<dyn Snapshot>,
+}
+
+/// Registers the family for the text format and keeps it around for the others
+pub fn register<F: Metric + Snapshot + Clone>(
+    registry: &mut Registry,
+    described: &mut Vec<Described>,
+    name: String,
+    help: &str,
+    family: &F,
+) {
+    registry.register(name.clone(), help, family.clone());
+    described.push(Described {
+        name,
+        help: format!("{help}."),
+        family: Box::new(family.clone()),
+    });
+}
+
+/// Renders the families of a registry, which has to hold no summaries for
+/// OpenMetrics, the library can't encode them
+pub fn encode_families(
+    registry: &Registry,
+    described: &[Described],
+    format: Format,
+) -> Result<Vec<u8>> {
+    match format {
+        Format::Protobuf => Ok(protobuf::encode(&families(described))),
+        Format::Text => Ok(text::encode(&families(described))?.into_bytes()),
+        Format::OpenMetrics => {
+            let mut buffer = String::new();
+            encode(&mut buffer, registry)?;
+            Ok(buffer.into_bytes())
+        }
+    }
+}
+
+// the current values of every family with members
+fn families(described: &[Described]) -> Vec<Family> {
+    described
+        .iter()
+        .map(|described| Family {
+            name: described.name.clone(),
+            help: described.help.clone(),
+            kind: described.family.kind(),
+            samples: described.family.samples(),
+        })
+        .filter(|family| !family.samples.is_empty())
+        .collect()
+}
+
+/// A registry with the simulated server metrics registered in it. The fleet
+/// wide /metrics has one, and so does every host with a listener of its own.
+pub struct Exporter {
+    // Mutex for safe mutable access
+    registry: Mutex<Registry>,
+    health: SortedFamily<HostLabels, Gauge>,
+    // AtomicU64 for floating points, default is i64 for some reason
+    cpu: SortedFamily<CpuLabels, Gauge<f64, AtomicU64>>,
+    mem_total: SortedFamily<HostLabels, Gauge<f64, AtomicU64>>,
+    mem_used: SortedFamily<HostLabels, Gauge<f64, AtomicU64>>,
+    disk_total: SortedFamily<DiskLabels, Gauge>,
+    disk_used: SortedFamily<DiskLabels, Gauge>,
+    inodes_total: SortedFamily<DiskLabels, Gauge>,
+    inodes_used: SortedFamily<DiskLabels, Gauge>,
+    disk_read_bytes: SortedFamily<DiskLabels, Counter>,
+    disk_written_bytes: SortedFamily<DiskLabels, Counter>,
+    disk_reads: SortedFamily<DiskLabels, Counter>,
+    disk_writes: SortedFamily<DiskLabels, Counter>,
+    disk_latency: SortedFamily<DiskLabels, Gauge<f64, AtomicU64>>,
+    net: [SortedFamily<NetLabels, Counter>; 8],
+    latency: SortedFamily<EndpointLabels, MirroredHistogram>,
+    // not in the registry, prometheus_client can't encode summaries
+    latency_summary: SortedFamily<EndpointLabels, MirroredSummary>,
+    // every family, in the order of the registry
+    described: Vec<Described>,
+}
+
+impl Exporter {
+    // register the metrics in the register to be collected when the scraping happens
+    pub fn new(namespace: &str) -> Exporter {
+        let mut exporter = Exporter {
+            registry: Mutex::new(Registry::default()),
+            health: SortedFamily::default(),
+            cpu: SortedFamily::default(),
+            mem_total: SortedFamily::default(),
+            mem_used: SortedFamily::default(),
+            disk_total: SortedFamily::default(),
+            disk_used: SortedFamily::default(),
+            inodes_total: SortedFamily::default(),
+            inodes_used: SortedFamily::default(),
+            disk_read_bytes: SortedFamily::default(),
+            disk_written_bytes: SortedFamily::default(),
+            disk_reads: SortedFamily::default(),
+            disk_writes: SortedFamily::default(),
+            disk_latency: SortedFamily::default(),
+            net: Default::default(),
+            latency: SortedFamily::default(),
+            latency_summary: SortedFamily::default(),
+            described: Vec::new(),
+        };
+
+        let mut described = Vec::new();
+        let mut registry = exporter.registry();
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_health"),
+            "server health",
+            &exporter.health,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_cpu_load"),
+            "CPU load average",
+            &exporter.cpu,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_memory_bytes_total"),
+            "total memory in bytes",
+            &exporter.mem_total,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_memory_bytes_used"),
+            "used memory in bytes",
+            &exporter.mem_used,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_bytes_total"),
+            "size of the filesystem in bytes",
+            &exporter.disk_total,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_bytes_used"),
+            "used space on the filesystem in bytes",
+            &exporter.disk_used,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_inodes_total"),
+            "inodes of the filesystem",
+            &exporter.inodes_total,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_inodes_used"),
+            "inodes in use on the filesystem",
+            &exporter.inodes_used,
+        );
+
+        // counters get their _total suffix from the encoder
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_read_bytes"),
+            "bytes read from the disk",
+            &exporter.disk_read_bytes,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_written_bytes"),
+            "bytes written to the disk",
+            &exporter.disk_written_bytes,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_reads_completed"),
+            "reads completed on the disk",
+            &exporter.disk_reads,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_writes_completed"),
+            "writes completed on the disk",
+            &exporter.disk_writes,
+        );
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_disk_io_latency_seconds"),
+            "average time an I/O took recently",
+            &exporter.disk_latency,
+        );
+
+        for ((name, help), family) in NET_COUNTERS.iter().zip(&exporter.net) {
+            register(
+                &mut registry,
+                &mut described,
+                format!("{namespace}_{name}"),
+                help,
+                family,
+            );
+        }
+
+        register(
+            &mut registry,
+            &mut described,
+            format!("{namespace}_request_duration_seconds"),
+            "latency of the requests served",
+            &exporter.latency,
+        );
+        drop(registry);
+
+        // summaries are encoded by hand, see encode_summaries
+        described.push(Described {
+            name: format!("{namespace}_request_duration_summary_seconds"),
+            help: "latency of the requests served over the last five minutes.".to_string(),
+            family: Box::new(exporter.latency_summary.clone()),
+        });
+        exporter.described = described;
+
+        exporter
+    }
+
+    // a panic while holding the lock must not take /metrics down for good
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes in a snapshot of each host, by host id, and renders them in the
+    /// given format
+    pub fn scrape<'a>(
+        &self,
+        snapshots: impl IntoIterator<Item = (&'a str, Option<HostSnapshot>)>,
+        format: Format,
+    ) -> Result<Vec<u8>> {
+        // hold the registry until encoded, so concurrent scrapes don't see each
+        // other's values
+        let registry = self.registry();
+        for (id, snapshot) in snapshots {
+            self.populate(id, snapshot.as_ref());
+        }
+
+        match format {
+            Format::OpenMetrics => {}
+            _ => return encode_families(&registry, &self.described, format),
+        }
+
+        // generate openmetrics response
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry)?;
+
+        // the summaries go last, ahead of the closing # EOF
+        let eof = buffer.len() - "# EOF\n".len();
+        let mut summaries = String::new();
+        self.encode_summaries(&mut summaries)?;
+        buffer.insert_str(eof, &summaries);
+        Ok(buffer.into_bytes())
+    }
+
+    fn snapshot(&self) -> Vec<Family> {
+        families(&self.described)
+    }
+
+    // written out by hand, escaped the same way in both text formats
+    fn encode_summaries(&self, out: &mut String) -> std::fmt::Result {
+        let summaries = self
+            .snapshot()
+            .into_iter()
+            .filter(|family| family.kind == Kind::Summary);
+        for family in summaries {
+            let name = &family.name;
+            writeln!(out, "# HELP {name} {}", family.help)?;
+            writeln!(out, "# TYPE {name} summary")?;
+            for sample in family.samples {
+                let Value::Summary(totals) = sample.value else {
+                    continue;
+                };
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(label, value)| format!("{label}=\"{}\"", text::escape_label(value)))
+                    .collect();
+                let labels = labels.join(",");
+                for (quantile, value) in totals.quantiles {
+                    writeln!(out, "{name}{{{labels},quantile=\"{quantile}\"}} {value}")?;
+                }
+                writeln!(out, "{name}_sum{{{labels}}} {}", totals.sum)?;
+                writeln!(out, "{name}_count{{{labels}}} {}", totals.count)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every series of a host that is gone from the fleet
+    pub fn forget(&self, id: &str) {
+        let _registry = self.registry();
+        self.health.remove(&HostLabels {
+            host: id.to_string(),
+        });
+        self.forget_samples(id);
+    }
+
+    // everything but the health
+    fn forget_samples(&self, id: &str) {
+        self.cpu.retain(|labels| labels.host != id);
+        for family in [&self.mem_used, &self.mem_total] {
+            family.retain(|labels| labels.host != id);
+        }
+        for family in [
+            &self.disk_total,
+            &self.disk_used,
+            &self.inodes_total,
+            &self.inodes_used,
+        ] {
+            family.retain(|labels| labels.host != id);
+        }
+        for family in [
+            &self.disk_read_bytes,
+            &self.disk_written_bytes,
+            &self.disk_reads,
+            &self.disk_writes,
+        ] {
+            family.retain(|labels| labels.host != id);
+        }
+        self.disk_latency.retain(|labels| labels.host != id);
+        for family in &self.net {
+            family.retain(|labels| labels.host != id);
+        }
+        self.latency.retain(|labels| labels.host != id);
+        self.latency_summary.retain(|labels| labels.host != id);
+    }
+
+    // populate registered metrics from what the host reported
+    fn populate(&self, id: &str, snapshot: Option<&HostSnapshot>) {
+        let host_labels = HostLabels {
+            host: id.to_string(),
+        };
+        let cpu_labels = |bucket: &str| CpuLabels {
+            host: id.to_string(),
+            bucket: bucket.to_string(),
+        };
+
+        // a host that is down has nothing to report but that
+        let Some(snapshot) = snapshot else {
+            self.health.get_or_create(&host_labels).set(0);
+            self.forget_samples(id);
+            return;
+        };
+        let metrics = &snapshot.metrics;
+
+        self.health
+            .get_or_create(&host_labels)
+            .set(i64::from(snapshot.healthy));
+
+        let cpu_metrics = &metrics.cpu;
+        self.cpu
+            .get_or_create(&cpu_labels("1m"))
+            .set(cpu_metrics.load_1m);
+
+        self.cpu
+            .get_or_create(&cpu_labels("5m"))
+            .set(cpu_metrics.load_5m);
+
+        self.cpu
+            .get_or_create(&cpu_labels("15m"))
+            .set(cpu_metrics.load_15m);
+
+        let mem_metrics = &metrics.memory;
+        self.mem_used
+            .get_or_create(&host_labels)
+            .set(mem_metrics.used_bytes as f64);
+        self.mem_total
+            .get_or_create(&host_labels)
+            .set(mem_metrics.total_bytes as f64);
+
+        for disk in &metrics.disk {
+            let labels = DiskLabels {
+                host: id.to_string(),
+                mount: disk.mount.clone(),
+            };
+            self.disk_total
+                .get_or_create(&labels)
+                .set(disk.total_bytes as i64);
+            self.disk_used
+                .get_or_create(&labels)
+                .set(disk.used_bytes as i64);
+            self.inodes_total
+                .get_or_create(&labels)
+                .set(disk.total_inodes as i64);
+            self.inodes_used
+                .get_or_create(&labels)
+                .set(disk.used_inodes as i64);
+
+            // the model keeps the running totals, the counters just mirror them
+            for (family, value) in [
+                (&self.disk_read_bytes, disk.read_bytes),
+                (&self.disk_written_bytes, disk.written_bytes),
+                (&self.disk_reads, disk.reads),
+                (&self.disk_writes, disk.writes),
+            ] {
+                family
+                    .get_or_create(&labels)
+                    .inner()
+                    .store(value, Ordering::Relaxed);
+            }
+
+            self.disk_latency
+                .get_or_create(&labels)
+                .set(disk.latency_seconds);
+        }
+
+        for net in &metrics.network {
+            let labels = NetLabels {
+                host: id.to_string(),
+                device: net.device.clone(),
+            };
+            for (family, value) in self.net.iter().zip(net_values(net)) {
+                family
+                    .get_or_create(&labels)
+                    .inner()
+                    .store(value, Ordering::Relaxed);
+            }
+        }
+
+        for latency in &metrics.latency {
+            let labels = EndpointLabels {
+                host: id.to_string(),
+                endpoint: latency.endpoint.clone(),
+            };
+            let buckets: Vec<(f64, u64)> = latency
+                .buckets
+                .iter()
+                .map(|bucket| (bucket.le, bucket.count))
+                .collect();
+            let native = NativeBuckets {
+                schema: latency.native.schema,
+                zero_threshold: ZERO_THRESHOLD,
+                zero_count: 0,
+                positive: latency.native.buckets.clone(),
+            };
+            self.latency.get_or_create(&labels).set(
+                latency.sum_seconds,
+                latency.count,
+                &buckets,
+                Some(native),
+            );
+
+            self.latency_summary
+                .get_or_create(&labels)
+                .set(SummaryTotals {
+                    quantiles: latency
+                        .quantiles
+                        .iter()
+                        .map(|q| (q.quantile, q.seconds.unwrap_or(f64::NAN)))
+                        .collect(),
+                    sum: latency.sum_seconds,
+                    count: latency.count,
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Profile};
+    use crate::sim::Sim;
+    use crate::source::{MetricSource, RandomSource};
+    use std::fs;
+    use std::path::PathBuf;
+
+    // a few ticks into a seeded run with simulated time, the same on every machine
+    fn snapshot() -> HostSnapshot {
+        let config = Config::default();
+        let profile = Profile::default();
+        let sim = Sim::new(
+            "host-1",
+            7,
+            &profile,
+            Some(15000),
+            &config.latency_buckets,
+            config.native_histogram_schema,
+            None,
+        );
+        let mut source = RandomSource::new(sim, profile);
+        let mut snapshots = std::iter::from_fn(|| Some(source.snapshot()));
+        snapshots
+            .by_ref()
+            .take(10)
+            .flatten()
+            .last()
+            .expect("a seeded run with no scenario has no outage")
+    }
+
+    // compares with testdata/<name>, or rewrites it with UPDATE_GOLDEN=1
+    fn golden(format: Format, name: &str) {
+        let exporter = Exporter::new("my_server_instr");
+        let scraped = exporter
+            .scrape([("host-1", Some(snapshot()))], format)
+            .unwrap();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &scraped).unwrap();
+        }
+        let expected = fs::read(&path).unwrap();
+        assert!(
+            scraped == expected,
+            "{name} changed, rerun with UPDATE_GOLDEN=1 if that was intended"
+        );
+    }
+
+    #[test]
+    fn encodes_prometheus_text() {
+        golden(Format::Text, "scrape.prom");
+    }
+
+    #[test]
+    fn encodes_openmetrics() {
+        golden(Format::OpenMetrics, "scrape.openmetrics");
+    }
+
+    #[test]
+    fn encodes_delimited_protobuf() {
+        golden(Format::Protobuf, "scrape.pb");
+    }
+
+    #[test]
+    fn scrapes_the_same_snapshot_the_same() {
+        let exporter = Exporter::new("my_server_instr");
+        let snapshot = snapshot();
+        let first = exporter
+            .scrape([("host-1", Some(snapshot.clone()))], Format::Text)
+            .unwrap();
+        let second = exporter
+            .scrape([("host-1", Some(snapshot))], Format::Text)
+            .unwrap();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,127 @@
+use prometheus_client::encoding::{EncodeLabelSet, EncodeMetric, MetricEncoder};
+use prometheus_client::metrics::{MetricType, TypedMetric};
+use std::collections::BTreeMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Same idea as prometheus_client's `Family`, but members are encoded in label
+/// order rather than hash order, so the same values always render to the same
+/// bytes. Clones share the same members, like the metrics they hold.
+#[derive(Debug)]
+pub struct SortedFamily<S, M> {
+    metrics: Arc<RwLock<BTreeMap<S, M>>>,
+}
+
+impl<S, M> Default for SortedFamily<S, M> {
+    fn default() -> Self {
+        SortedFamily {
+            metrics: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+}
+
+impl<S, M> Clone for SortedFamily<S, M> {
+    fn clone(&self) -> Self {
+        SortedFamily {
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl<S: Clone + Ord, M: Clone + Default> SortedFamily<S, M> {
+    /// Returns the member for the label set, creating it if needed. Metrics are
+    /// cheap handles to shared values, so the returned clone updates the member.
+    pub fn get_or_create(&self, label_set: &S) -> M {
+        if let Some(metric) = self
+            .metrics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(label_set)
+        {
+            return metric.clone();
+        }
+
+        self.metrics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(label_set.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// The members with their label sets, in label order
+    pub fn members(&self) -> Vec<(S, M)> {
+        self.metrics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(label_set, metric)| (label_set.clone(), metric.clone()))
+            .collect()
+    }
+
+    /// Drops every member whose label set doesn't pass `keep`
+    pub fn retain(&self, mut keep: impl FnMut(&S) -> bool) {
+        self.metrics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|label_set, _| keep(label_set));
+    }
+
+    /// Drops the member for the label set, returns whether there was one
+    pub fn remove(&self, label_set: &S) -> bool {
+        self.metrics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(label_set)
+            .is_some()
+    }
+}
+
+impl<S, M: TypedMetric> TypedMetric for SortedFamily<S, M> {
+    const TYPE: MetricType = M::TYPE;
+}
+
+impl<S, M> EncodeMetric for SortedFamily<S, M>
+where
+    S: EncodeLabelSet,
+    M: EncodeMetric + TypedMetric,
+{
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        for (label_set, metric) in self
+            .metrics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            metric.encode(encoder.encode_family(label_set)?)?;
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::metrics::gauge::Gauge;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn carries_on_after_a_panic_under_the_lock() {
+        let family = SortedFamily::<String, Gauge>::default();
+        family.get_or_create(&"a".into()).set(1);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            family.retain(|_| panic!("while holding the write lock"))
+        }));
+        assert!(panicked.is_err());
+
+        family.get_or_create(&"b".into()).set(2);
+        assert!(family.remove(&"a".into()));
+        let members = family.members();
+        assert_eq!(members.len(), 1);
+        assert_eq!((members[0].0.as_str(), members[0].1.get()), ("b", 2));
+    }
+}
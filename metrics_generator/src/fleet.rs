@@ -0,0 +1,273 @@
+use crate::config::{Config, ConfigError, HostSpec, Source};
+use crate::error::Result;
+use crate::exporter::{Exporter, Format};
+use crate::procfs;
+use crate::scenario::Scenario;
+use crate::sim::Sim;
+use crate::source::{
+    self, HostSnapshot, HostSource, MetricSource, RandomSource, Recorded, ReplaySource,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use tracing::{error, info};
+
+/// One simulated server, with a source of values of its own
+pub struct Host {
+    pub id: String,
+    pub profile_name: String,
+    /// port of the host's own listener, if hosts get one
+    pub port: Option<u16>,
+    pub datacenter: Option<String>,
+    pub role: Option<String>,
+    /// the metrics served on the host's own listener
+    pub exporter: Exporter,
+    source: Mutex<Box<dyn MetricSource>>,
+    // what the last tick saw, served to every endpoint alike
+    current: RwLock<Option<HostSnapshot>>,
+}
+
+impl Host {
+    /// The host's values as of the last tick, None while it is down
+    pub fn snapshot(&self) -> Option<HostSnapshot> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Moves the host on to a new snapshot
+    pub fn tick(&self) {
+        let snapshot = self
+            .source
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .snapshot();
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = snapshot;
+    }
+}
+
+/// All the simulated servers, in the order they were added
+pub struct Fleet {
+    hosts: RwLock<Vec<Arc<Host>>>,
+    /// the metrics of every host, served on the main listener
+    pub exporter: Exporter,
+    // hands out the seeds of hosts added later on too
+    seeds: Mutex<StdRng>,
+    namespace: String,
+    source: Source,
+    // read once, every host plays it back on its own
+    replay: Option<Arc<[Recorded]>>,
+    time_step_ms: Option<u64>,
+    latency_buckets: Vec<f64>,
+    native_histogram_schema: i32,
+}
+
+impl Fleet {
+    pub fn new(config: &Config) -> std::result::Result<Fleet, ConfigError> {
+        // pick a seed even when none is given, so an interesting run can be replayed
+        let seed = config.seed.unwrap_or_else(rand::random);
+        info!(seed, "generating values");
+        if config.source == Source::Host {
+            let cpu = procfs::cpu().map_err(|err| host_source_error(&err))?;
+            procfs::memory().map_err(|err| host_source_error(&err))?;
+            info!(
+                threads = cpu.thread_count,
+                "reading CPU and memory from /proc"
+            );
+        }
+
+        let replay = match (config.source, &config.replay_path) {
+            (Source::Replay, Some(path)) => {
+                let recorded = source::read_replay(path)?;
+                info!(
+                    snapshots = recorded.len(),
+                    path = %path.display(),
+                    "replaying recorded snapshots"
+                );
+                Some(recorded)
+            }
+            _ => None,
+        };
+
+        // every host draws from its own generator, so requests for one host
+        // don't change what another one reports
+        let fleet = Fleet {
+            hosts: RwLock::new(Vec::new()),
+            exporter: Exporter::new(&config.namespace),
+            seeds: Mutex::new(StdRng::seed_from_u64(seed)),
+            namespace: config.namespace.clone(),
+            source: config.source,
+            replay,
+            time_step_ms: config.time_step_ms,
+            latency_buckets: config.latency_buckets.clone(),
+            native_histogram_schema: config.native_histogram_schema,
+        };
+
+        for spec in config.hosts()? {
+            fleet.add(spec)?;
+        }
+        Ok(fleet)
+    }
+
+    /// Starts reporting another host, fails if the id is taken
+    pub fn add(&self, spec: HostSpec) -> std::result::Result<(), ConfigError> {
+        let HostSpec {
+            id,
+            profile_name,
+            profile,
+            scenario,
+            port,
+            datacenter,
+            role,
+        } = spec;
+        let scenario = scenario.as_deref().map(Scenario::from_file).transpose()?;
+        // the load model and the rest go by the machine's actual size
+        let mut profile = profile;
+        if self.source == Source::Host {
+            profile.core_count = procfs::cpuinfo()
+                .map_err(|err| host_source_error(&err))?
+                .cores;
+            profile.total_bytes = procfs::memory()
+                .map_err(|err| host_source_error(&err))?
+                .total_bytes;
+        }
+
+        let mut hosts = self.hosts.write().unwrap_or_else(PoisonError::into_inner);
+        if hosts.iter().any(|host| host.id == id) {
+            return Err(ConfigError::Invalid(format!("duplicate host id {id:?}")));
+        }
+
+        let seed = self
+            .seeds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .gen();
+        // chosen here, everything past this only sees the snapshots
+        let simulated = || {
+            let sim = Sim::new(
+                &id,
+                seed,
+                &profile,
+                self.time_step_ms,
+                &self.latency_buckets,
+                self.native_histogram_schema,
+                scenario,
+            );
+            RandomSource::new(sim, profile)
+        };
+        let source: Box<dyn MetricSource> = match (self.source, &self.replay) {
+            (Source::Host, _) => Box::new(HostSource::new(simulated())),
+            (Source::Replay, Some(recorded)) => Box::new(ReplaySource::new(Arc::clone(recorded))),
+            _ => Box::new(simulated()),
+        };
+        let host = Host {
+            id,
+            profile_name,
+            port,
+            datacenter,
+            role,
+            exporter: Exporter::new(&self.namespace),
+            source: Mutex::new(source),
+            current: RwLock::new(None),
+        };
+        // so there is something to serve before the first tick
+        host.tick();
+        hosts.push(Arc::new(host));
+        Ok(())
+    }
+
+    /// Stops simulating a host, returns whether there was one by that id
+    pub fn remove(&self, id: &str) -> bool {
+        let mut hosts = self.hosts.write().unwrap_or_else(PoisonError::into_inner);
+        let Some(index) = hosts.iter().position(|host| host.id == id) else {
+            return false;
+        };
+        hosts.remove(index);
+        // still holding the hosts, so no scrape can bring the series back
+        self.exporter.forget(id);
+        true
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Host>> {
+        self.hosts().into_iter().find(|host| host.id == id)
+    }
+
+    /// The host behind the plain /stats and /healthz paths, if any are left
+    pub fn first(&self) -> Option<Arc<Host>> {
+        self.hosts().into_iter().next()
+    }
+
+    /// The hosts as they are right now
+    pub fn hosts(&self) -> Vec<Arc<Host>> {
+        self.hosts
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Moves every host on to a new snapshot. A host whose source panics
+    /// keeps its last one, and the others move on regardless.
+    pub fn tick(&self) {
+        for host in self.hosts() {
+            if panic::catch_unwind(AssertUnwindSafe(|| host.tick())).is_err() {
+                error!(host = %host.id, "recovered from a panicked tick");
+            }
+        }
+    }
+
+    /// Reports every host's last snapshot through the fleet wide exporter
+    pub fn scrape(&self, format: Format) -> Result<Vec<u8>> {
+        let hosts = self.hosts.read().unwrap_or_else(PoisonError::into_inner);
+        let snapshots = hosts.iter().map(|host| (host.id.as_str(), host.snapshot()));
+        self.exporter.scrape(snapshots, format)
+    }
+}
+
+fn host_source_error(err: &std::io::Error) -> ConfigError {
+    ConfigError::Invalid(format!("source \"host\" needs /proc: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Panicking;
+
+    impl MetricSource for Panicking {
+        fn snapshot(&mut self) -> Option<HostSnapshot> {
+            panic!("broken source");
+        }
+    }
+
+    #[test]
+    fn ticks_on_past_a_panicking_source() {
+        let config = Config {
+            seed: Some(1),
+            host_count: Some(2),
+            ..Config::default()
+        };
+        let fleet = Fleet::new(&config).unwrap();
+        let broken = fleet.first().unwrap();
+        *broken.source.lock().unwrap() = Box::new(Panicking);
+        let before = fleet.hosts()[1]
+            .snapshot()
+            .unwrap()
+            .metrics
+            .memory
+            .used_bytes;
+
+        fleet.tick();
+        fleet.tick();
+        // the broken host serves what it had, the other one moved on
+        assert!(broken.snapshot().is_some());
+        let after = fleet.hosts()[1]
+            .snapshot()
+            .unwrap()
+            .metrics
+            .memory
+            .used_bytes;
+        assert_ne!(before, after);
+    }
+}
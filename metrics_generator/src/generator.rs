@@ -0,0 +1,218 @@
+use crate::config::Profile;
+use crate::disk::DiskModel;
+use crate::load::LoadModel;
+use crate::net::NetModel;
+use crate::sim::Sim;
+use crate::workload::{Workload, QUANTILES};
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsRoot {
+    pub cpu: MetricsCpu,
+    pub memory: MetricsMem,
+    pub disk: Vec<MetricsDisk>,
+    pub network: Vec<MetricsNet>,
+    pub latency: Vec<MetricsLatency>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsCpu {
+    pub load_1m: f64,
+    pub load_5m: f64,
+    pub load_15m: f64,
+    pub thread_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsMem {
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsDisk {
+    pub mount: String,
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+    pub used_inodes: u64,
+    pub total_inodes: u64,
+    // counters since the simulated box came up
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub latency_seconds: f64,
+}
+
+// counters since the simulated box came up, they start over on a restart
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsNet {
+    pub device: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+// request latencies of an endpoint since the simulated box came up
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsLatency {
+    pub endpoint: String,
+    pub count: u64,
+    pub sum_seconds: f64,
+    /// cumulative, the +Inf bucket being `count`
+    pub buckets: Vec<MetricsBucket>,
+    /// over the last five minutes, null without requests in that time
+    pub quantiles: Vec<MetricsQuantile>,
+    pub native: MetricsNative,
+}
+
+/// Exponential buckets as in a native histogram, bucket i holding the
+/// latencies in (base^(i-1), base^i] with base = 2^(2^-schema)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsNative {
+    pub schema: i32,
+    /// count per bucket index, not cumulative
+    #[serde(deserialize_with = "index_keys")]
+    pub buckets: BTreeMap<i32, u64>,
+}
+
+// JSON has the indices as strings, which serde only turns back into numbers
+// outside of a #[serde(flatten)], and replayed snapshots are read through one
+fn index_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<i32, u64>, D::Error> {
+    BTreeMap::<String, u64>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(index, count)| {
+            let index = index
+                .parse()
+                .map_err(|_| de::Error::custom(format!("bucket index {index:?} is no i32")))?;
+            Ok((index, count))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsBucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsQuantile {
+    pub quantile: f64,
+    pub seconds: Option<f64>,
+}
+
+pub fn gen_health_status(sim: &mut Sim, profile: &Profile) -> bool {
+    if let Some(healthy) = sim.phase().and_then(|(phase, into)| phase.healthy(into)) {
+        return healthy;
+    }
+
+    !sim.rng.gen_bool(profile.unhealthy_chance)
+}
+
+pub fn gen_metrics_mem(sim: &mut Sim, total_bytes: u64) -> MetricsMem {
+    let share = sim
+        .phase()
+        .and_then(|(phase, into)| phase.memory_share(into));
+
+    let used_bytes = match share {
+        // follow the scenario, give or take a percent
+        Some(share) => {
+            let jitter = sim.rng.gen_range(-0.01..0.01);
+            (total_bytes as f64 * (share + jitter).clamp(0.0, 1.0)) as u64
+        }
+        // used memory stayes between mid point and full usage
+        None => sim.rng.gen_range(total_bytes / 2..total_bytes),
+    };
+
+    MetricsMem {
+        used_bytes,
+        total_bytes,
+    }
+}
+
+pub fn gen_metrics_cpu(load: &LoadModel, core_count: u32) -> MetricsCpu {
+    let [load_1m, load_5m, load_15m] = load.averages();
+
+    MetricsCpu {
+        load_1m,
+        load_5m,
+        load_15m,
+        thread_count: core_count * 2,
+    }
+}
+
+pub fn gen_metrics_disk(disks: &DiskModel) -> Vec<MetricsDisk> {
+    disks
+        .mounts()
+        .iter()
+        .map(|mount| MetricsDisk {
+            mount: mount.disk.mount.clone(),
+            used_bytes: mount.used_bytes as u64,
+            total_bytes: mount.disk.total_bytes,
+            used_inodes: mount.used_inodes as u64,
+            total_inodes: mount.disk.total_inodes(),
+            read_bytes: mount.read_bytes,
+            written_bytes: mount.written_bytes,
+            reads: mount.reads,
+            writes: mount.writes,
+            latency_seconds: mount.latency_secs,
+        })
+        .collect()
+}
+
+pub fn gen_metrics_net(net: &NetModel) -> Vec<MetricsNet> {
+    net.links()
+        .iter()
+        .map(|link| {
+            let counters = link.counters;
+            MetricsNet {
+                device: link.interface.device.clone(),
+                rx_bytes: counters.rx_bytes,
+                tx_bytes: counters.tx_bytes,
+                rx_packets: counters.rx_packets,
+                tx_packets: counters.tx_packets,
+                rx_errors: counters.rx_errors,
+                tx_errors: counters.tx_errors,
+                rx_dropped: counters.rx_dropped,
+                tx_dropped: counters.tx_dropped,
+            }
+        })
+        .collect()
+}
+
+pub fn gen_metrics_latency(workload: &Workload) -> Vec<MetricsLatency> {
+    workload
+        .endpoints()
+        .iter()
+        .map(|served| MetricsLatency {
+            endpoint: served.endpoint.path.clone(),
+            count: served.count,
+            sum_seconds: served.sum_secs,
+            buckets: served
+                .buckets
+                .iter()
+                .map(|&(le, count)| MetricsBucket { le, count })
+                .collect(),
+            quantiles: QUANTILES
+                .iter()
+                .zip(served.quantiles())
+                .map(|(&quantile, seconds)| MetricsQuantile {
+                    quantile,
+                    seconds: (!seconds.is_nan()).then_some(seconds),
+                })
+                .collect(),
+            native: MetricsNative {
+                schema: workload.schema,
+                buckets: served.native.clone(),
+            },
+        })
+        .collect()
+}
@@ -0,0 +1,143 @@
+use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+use prometheus_client::metrics::exemplar::Exemplar;
+use prometheus_client::metrics::{MetricType, TypedMetric};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// A histogram that mirrors running totals kept by the simulation, the way the
+/// counters do with `inner().store()`. prometheus_client's `Histogram` can only
+/// be observed into, and its buckets can't be read back for /stats.
+#[derive(Debug, Default, Clone)]
+pub struct MirroredHistogram {
+    inner: Arc<RwLock<HistogramTotals>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HistogramTotals {
+    pub sum: f64,
+    pub count: u64,
+    /// per bucket rather than cumulative, the way the encoder wants them,
+    /// ending with the +Inf one as f64::MAX
+    pub buckets: Vec<(f64, u64)>,
+    /// the exponential buckets, only the protobuf format has room for them
+    pub native: Option<NativeBuckets>,
+}
+
+/// The buckets of a native histogram, all of them above the zero bucket
+#[derive(Debug, Default, Clone)]
+pub struct NativeBuckets {
+    pub schema: i32,
+    pub zero_threshold: f64,
+    pub zero_count: u64,
+    /// count per bucket index, empty buckets left out
+    pub positive: BTreeMap<i32, u64>,
+}
+
+impl MirroredHistogram {
+    /// Takes over the totals, `buckets` being cumulative counts per upper
+    /// bound like in the exposition format, without the +Inf one. Counts that
+    /// go down make for empty buckets rather than wrapping around.
+    pub fn set(&self, sum: f64, count: u64, buckets: &[(f64, u64)], native: Option<NativeBuckets>) {
+        let mut below = 0;
+        let mut per_bucket: Vec<(f64, u64)> = buckets
+            .iter()
+            .map(|&(bound, cumulative)| {
+                let in_bucket = cumulative.saturating_sub(below);
+                below = below.max(cumulative);
+                (bound, in_bucket)
+            })
+            .collect();
+        // the encoder renders f64::MAX as +Inf
+        per_bucket.push((f64::MAX, count.saturating_sub(below)));
+
+        let mut totals = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        *totals = HistogramTotals {
+            sum,
+            count,
+            buckets: per_bucket,
+            native,
+        };
+    }
+
+    /// Counts one observation into buckets with the given upper bounds, for
+    /// a histogram kept here rather than by the simulation
+    pub fn observe(&self, bounds: &[f64], value: f64) {
+        let mut totals = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        if totals.buckets.is_empty() {
+            totals.buckets = bounds.iter().map(|&bound| (bound, 0)).collect();
+            totals.buckets.push((f64::MAX, 0));
+        }
+        if let Some((_, count)) = totals.buckets.iter_mut().find(|(bound, _)| value <= *bound) {
+            *count += 1;
+        }
+        totals.sum += value;
+        totals.count += 1;
+    }
+
+    pub fn get(&self) -> HistogramTotals {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl TypedMetric for MirroredHistogram {
+    const TYPE: MetricType = MetricType::Histogram;
+}
+
+impl EncodeMetric for MirroredHistogram {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        let totals = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        encoder.encode_histogram(
+            totals.sum,
+            totals.count,
+            &totals.buckets,
+            None::<&HashMap<usize, Exemplar<(), f64>>>,
+        )
+    }
+
+    fn metric_type(&self) -> MetricType {
+        Self::TYPE
+    }
+}
+
+/// Quantiles with their sum and count, for a summary. Like histograms the
+/// simulation keeps the observations, this only holds what gets reported.
+#[derive(Debug, Default, Clone)]
+pub struct MirroredSummary {
+    inner: Arc<RwLock<SummaryTotals>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SummaryTotals {
+    pub quantiles: Vec<(f64, f64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl MirroredSummary {
+    pub fn set(&self, totals: SummaryTotals) {
+        *self.inner.write().unwrap_or_else(PoisonError::into_inner) = totals;
+    }
+
+    pub fn get(&self) -> SummaryTotals {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_counts_that_do_not_add_up_from_wrapping() {
+        let histogram = MirroredHistogram::default();
+        histogram.set(1.0, 2, &[(0.1, 3), (0.5, 1)], None);
+        let buckets: Vec<u64> = histogram.get().buckets.iter().map(|(_, n)| *n).collect();
+        assert_eq!(buckets, [3, 0, 0]);
+    }
+}
@@ -0,0 +1,522 @@
+use crate::error::{Error, Result};
+use std::io::{self, BufRead, Read, Write};
+use std::time::SystemTime;
+
+// anything bigger than these is not a scrape, refuse to buffer it
+const MAX_REQUEST_LINE_BYTES: u64 = 8 * 1024;
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+const MAX_HEADER_COUNT: usize = 100;
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// First value of the header, header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// First value of the query string parameter
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client wants to send more requests on this connection
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or_default();
+        let has_token = |token: &str| {
+            connection
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+
+        match self.version {
+            Version::Http11 => !has_token("close"),
+            Version::Http10 => has_token("keep-alive"),
+        }
+    }
+}
+
+/// Reads the next request off the connection. Returns `None` when the client
+/// closes or goes quiet before sending anything, which is how idle keep-alive
+/// connections end.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    // peek first, so a client that hangs up or idles out between requests
+    // isn't mistaken for a broken one
+    match reader.fill_buf() {
+        Ok([]) => return Ok(None),
+        Ok(_) => {}
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut request_line = read_line(reader, MAX_REQUEST_LINE_BYTES, Error::UriTooLong)?;
+    // be lenient and skip a stray empty line before the request (RFC 9112 2.2)
+    if request_line.is_empty() {
+        request_line = read_line(reader, MAX_REQUEST_LINE_BYTES, Error::UriTooLong)?;
+    }
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && target.starts_with('/') =>
+        {
+            (method, target, version)
+        }
+        _ => return Err(Error::Parse(format!("bad request line {request_line:?}"))),
+    };
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other if other.starts_with("HTTP/") => {
+            return Err(Error::VersionNotSupported(other.to_string()))
+        }
+        other => return Err(Error::Parse(format!("bad protocol {other:?}"))),
+    };
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (target, Vec::new()),
+    };
+
+    let headers = read_headers(reader)?;
+    let mut request = Request {
+        method: method.to_string(),
+        path: percent_decode(path),
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.version == Version::Http11 && request.header("Host").is_none() {
+        return Err(Error::Parse("missing Host header".into()));
+    }
+
+    request.body = read_body(reader, &request)?;
+    Ok(Some(request))
+}
+
+// reads one CRLF (or bare LF) terminated line
+fn read_line<R: BufRead>(reader: &mut R, limit: u64, too_long: Error) -> Result<String> {
+    let mut line = Vec::new();
+    let read = reader.by_ref().take(limit).read_until(b'\n', &mut line)?;
+
+    if line.last() != Some(&b'\n') {
+        if read as u64 == limit {
+            return Err(too_long);
+        }
+        return Err(Error::Parse("connection closed mid request".into()));
+    }
+
+    let line = String::from_utf8(line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_headers<R: BufRead>(reader: &mut R) -> Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    let mut remaining = MAX_HEADER_BYTES;
+
+    loop {
+        let line = read_line(reader, remaining, Error::HeadersTooLarge)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+
+        remaining = remaining.saturating_sub(line.len() as u64 + 2);
+        if headers.len() == MAX_HEADER_COUNT || remaining == 0 {
+            return Err(Error::HeadersTooLarge);
+        }
+
+        // obsolete line folding is not allowed in requests (RFC 9112 5.2)
+        if line.starts_with([' ', '\t']) {
+            return Err(Error::Parse("folded header line".into()));
+        }
+
+        match line.split_once(':') {
+            Some((name, value)) if is_token(name) => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            _ => return Err(Error::Parse(format!("bad header line {line:?}"))),
+        }
+    }
+}
+
+fn read_body<R: BufRead>(reader: &mut R, request: &Request) -> Result<Vec<u8>> {
+    if let Some(encoding) = request.header("Transfer-Encoding") {
+        return Err(Error::NotImplemented(format!(
+            "transfer encoding {encoding:?}"
+        )));
+    }
+
+    let length = match request.header("Content-Length") {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| Error::Parse(format!("bad Content-Length {value:?}")))?,
+        None => return Ok(Vec::new()),
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(Error::BodyTooLarge);
+    }
+
+    let mut body = Vec::with_capacity(length as usize);
+    reader.by_ref().take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(Error::Parse("connection closed mid body".into()));
+    }
+    Ok(body)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(&key.replace('+', " ")),
+                percent_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds to the Vary header, for a response that depends on `name`
+    pub fn with_vary(mut self, name: &str) -> Response {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(header, _)| header.eq_ignore_ascii_case("Vary"))
+        {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(name);
+                self
+            }
+            None => self.with_header("Vary", name),
+        }
+    }
+
+    pub fn with_body(self, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        let mut response = self.with_header("Content-Type", content_type);
+        response.body = body.into();
+        response
+    }
+
+    /// Writes the response out. HEAD requests get the headers of the
+    /// equivalent GET, without the body.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        version: Version,
+        head_only: bool,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let mut head = format!(
+            "{} {} {}\r\n",
+            version.as_str(),
+            self.status,
+            reason_phrase(self.status)
+        );
+        head.push_str(&format!(
+            "Date: {}\r\n",
+            httpdate::fmt_http_date(SystemTime::now())
+        ));
+        // a 204 has no body, not even an empty one
+        if self.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str(if keep_alive {
+            "Connection: keep-alive\r\n"
+        } else {
+            "Connection: close\r\n"
+        });
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        if !head_only {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Cursor;
+
+    fn read(raw: &[u8]) -> Result<Option<Request>> {
+        read_request(&mut Cursor::new(raw))
+    }
+
+    // the status the server answers a request with that fails to parse
+    fn status(raw: &[u8]) -> Option<u16> {
+        read(raw).expect_err("request should be refused").status()
+    }
+
+    #[test]
+    fn reads_a_scrape() {
+        let request = read(b"GET /metrics?a=1&b=%2F HTTP/1.1\r\nHost: x\r\nAccept: */*\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+        assert_eq!(request.query("b"), Some("/"));
+        assert_eq!(request.header("accept"), Some("*/*"));
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn reads_nothing_from_a_closed_connection() {
+        assert!(read(b"").unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_a_body() {
+        let request = read(b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\n{}")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[test]
+    fn wants_a_host_on_http_11() {
+        assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert!(read(b"GET / HTTP/1.0\r\n\r\n").unwrap().is_some());
+    }
+
+    #[test]
+    fn refuses_a_long_request_line() {
+        let raw = format!("GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", "a".repeat(8 * 1024));
+        assert_eq!(status(raw.as_bytes()), Some(414));
+    }
+
+    #[test]
+    fn refuses_large_headers() {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nHost: x\r\nX-Big: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        );
+        assert_eq!(status(raw.as_bytes()), Some(431));
+    }
+
+    #[test]
+    fn refuses_many_headers() {
+        let headers: String = (0..=MAX_HEADER_COUNT)
+            .map(|i| format!("X-{i}: a\r\n"))
+            .collect();
+        let raw = format!("GET / HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+        assert_eq!(status(raw.as_bytes()), Some(431));
+    }
+
+    #[test]
+    fn refuses_bytes_that_are_not_utf8() {
+        assert_eq!(status(b"GET /\xff HTTP/1.1\r\nHost: x\r\n\r\n"), Some(400));
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nHost: \xc3\x28\r\n\r\n"),
+            Some(400)
+        );
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        assert_eq!(status(b"GET / HTTP/2.0\r\nHost: x\r\n\r\n"), Some(505));
+        assert_eq!(status(b"GET / SPDY/3\r\nHost: x\r\n\r\n"), Some(400));
+    }
+
+    #[test]
+    fn refuses_transfer_encoding() {
+        let raw =
+            b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(raw), Some(501));
+    }
+
+    #[test]
+    fn refuses_a_bad_content_length() {
+        for length in ["-1", "abc", "1, 1", ""] {
+            let raw =
+                format!("PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: {length}\r\n\r\n");
+            assert_eq!(
+                status(raw.as_bytes()),
+                Some(400),
+                "Content-Length {length:?}"
+            );
+        }
+        let raw = b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: 2000000\r\n\r\n";
+        assert_eq!(status(raw), Some(413));
+    }
+
+    #[test]
+    fn refuses_a_truncated_request() {
+        let raw = b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\n{}";
+        assert_eq!(status(raw), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: x"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1"), Some(400));
+    }
+
+    #[test]
+    fn refuses_malformed_lines() {
+        for raw in [
+            &b"GET\r\n\r\n"[..],
+            b"GET  / HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET metrics HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nBad Name: x\r\nHost: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n",
+        ] {
+            assert_eq!(status(raw), Some(400), "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    // random bytes and scrambled bits of requests may be refused, but must
+    // never bring the parser down
+    #[test]
+    fn survives_byte_soup() {
+        const PIECES: [&[u8]; 12] = [
+            b"GET ",
+            b"PUT ",
+            b"/metrics",
+            b" HTTP/1.1",
+            b" HTTP/1.0",
+            b"\r\n",
+            b"\n",
+            b"Host: x",
+            b"Content-Length: ",
+            b"Transfer-Encoding: chunked",
+            b":",
+            b"\xff\xfe",
+        ];
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..5000 {
+            let mut raw = Vec::new();
+            for _ in 0..rng.gen_range(0..40) {
+                if rng.gen_bool(0.5) {
+                    raw.extend_from_slice(PIECES[rng.gen_range(0..PIECES.len())]);
+                } else {
+                    let len = rng.gen_range(0..8);
+                    raw.extend((0..len).map(|_| rng.gen::<u8>()));
+                }
+            }
+            let mut reader = Cursor::new(&raw);
+            // keep reading like a keep-alive connection would
+            while let Ok(Some(_)) = read_request(&mut reader) {}
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use rand::Rng;
+use std::time::Duration;
+
+// the kernel recalculates the load averages every 5 seconds, with the decay
+// factors below (see calc_load() in kernel/sched/loadavg.c)
+pub const LOAD_FREQ: Duration = Duration::from_secs(5);
+const AVERAGE_WINDOWS_SECS: [f64; 3] = [60.0, 300.0, 900.0];
+/// After a long quiet spell an hour's worth of ticks settles everything, for
+/// the models that go through them one by one
+pub const MAX_CATCH_UP_TICKS: u64 = 720;
+
+// the run queue drifts around this share of the cores when nothing is going on
+const IDLE_SHARE: f64 = 0.4;
+// chance per tick of a busy period starting, roughly one every 15 minutes
+const BUSY_CHANCE: f64 = 0.005;
+
+/// Simulates the number of runnable tasks on a box and the 1, 5 and 15 minute
+/// load averages the kernel would derive from it.
+pub struct LoadModel {
+    core_count: u32,
+    run_queue: f64,
+    // ticks left in the current busy period, and how loaded it is
+    busy_ticks: u32,
+    busy_share: f64,
+    // run queue share set from outside, e.g. by a scenario phase
+    forced_share: Option<f64>,
+    averages: [f64; 3],
+}
+
+impl LoadModel {
+    /// Starts off as if the box had been idling for a while, rather than from
+    /// a fresh boot, so graphs don't begin with a ramp up
+    pub fn new(core_count: u32) -> LoadModel {
+        let run_queue = core_count as f64 * IDLE_SHARE;
+        LoadModel {
+            core_count,
+            run_queue,
+            busy_ticks: 0,
+            busy_share: 0.0,
+            forced_share: None,
+            averages: [run_queue; 3],
+        }
+    }
+
+    /// Pins the run queue to a share of the cores, or releases it with None
+    pub fn force_share(&mut self, share: Option<f64>) {
+        self.forced_share = share;
+    }
+
+    /// Moves the simulation forward by that many kernel ticks
+    pub fn advance(&mut self, rng: &mut impl Rng, ticks: u64) {
+        for _ in 0..ticks.min(MAX_CATCH_UP_TICKS) {
+            self.tick(rng);
+        }
+    }
+
+    fn tick(&mut self, rng: &mut impl Rng) {
+        let cores = self.core_count as f64;
+
+        if self.busy_ticks > 0 {
+            self.busy_ticks -= 1;
+        } else if rng.gen_bool(BUSY_CHANCE) {
+            // 1 to 5 minutes of work that may well be more than the box can take
+            self.busy_ticks = rng.gen_range(12..60);
+            self.busy_share = rng.gen_range(0.8..2.0);
+        }
+
+        let target = match self.forced_share {
+            Some(share) => cores * share,
+            None if self.busy_ticks > 0 => cores * self.busy_share,
+            None => cores * IDLE_SHARE,
+        };
+
+        // mean reverting walk towards the target, with some jitter
+        let jitter = rng.gen_range(-1.0..1.0) * cores * 0.1;
+        self.run_queue = (self.run_queue + 0.2 * (target - self.run_queue) + jitter).max(0.0);
+
+        // the kernel only ever sees whole tasks
+        let active = self.run_queue.round();
+        for (average, window) in self.averages.iter_mut().zip(AVERAGE_WINDOWS_SECS) {
+            let decay = (-LOAD_FREQ.as_secs_f64() / window).exp();
+            *average = *average * decay + active * (1.0 - decay);
+        }
+    }
+
+    /// The run queue as a share of the cores, 1.0 keeps every core busy
+    pub fn share(&self) -> f64 {
+        self.run_queue / self.core_count as f64
+    }
+
+    /// The 1, 5 and 15 minute load averages
+    pub fn averages(&self) -> [f64; 3] {
+        self.averages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // the kernel's fixed point decay factors per LOAD_FREQ, EXP_1, EXP_5 and
+    // EXP_15 over FIXED_1
+    const KERNEL_DECAY: [f64; 3] = [1884.0 / 2048.0, 2014.0 / 2048.0, 2037.0 / 2048.0];
+
+    #[test]
+    fn damps_like_the_kernel() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut load = LoadModel::new(100);
+        let mut expected = load.averages();
+        for _ in 0..MAX_CATCH_UP_TICKS {
+            load.advance(&mut rng, 1);
+            let active = (load.share() * 100.0).round();
+            for (average, decay) in expected.iter_mut().zip(KERNEL_DECAY) {
+                *average = *average * decay + active * (1.0 - decay);
+            }
+        }
+        for (got, expected) in load.averages().iter().zip(expected) {
+            assert!(
+                (got - expected).abs() < expected * 0.01,
+                "{got} vs {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn fifteen_minutes_lag_behind_one_after_a_step() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut load = LoadModel::new(100);
+        let [before, ..] = load.averages();
+        load.force_share(Some(1.0));
+
+        // a minute into the step
+        load.advance(&mut rng, 12);
+        let [one, five, fifteen] = load.averages().map(|average| (average - before) / 60.0);
+        assert!(one > 0.3, "{one}");
+        assert!(one > five && five > fifteen, "{one} {five} {fifteen}");
+        assert!(fifteen < 0.1, "{fifteen}");
+
+        // an hour on every average has caught up with the 100 runnable tasks
+        load.advance(&mut rng, MAX_CATCH_UP_TICKS);
+        for average in load.averages() {
+            assert!((average - 100.0).abs() < 10.0, "{average}");
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::config::{Config, ConfigError, LogFormat};
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload::{self, Handle};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Registry};
+
+// lets a reload swap the filter of the subscriber that's already installed
+static FILTER: OnceLock<Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Sends log lines to stdout, filtered by `log_level`, in `log_format`
+pub fn init(config: &Config) -> Result<(), ConfigError> {
+    let (filter, handle) = reload::Layer::new(filter(&config.log_level)?);
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = fmt::layer().with_ansi(io::stdout().is_terminal());
+    let installed = match config.log_format {
+        LogFormat::Text => registry.with(fmt).try_init(),
+        LogFormat::Json => registry.with(fmt.json().flatten_event(true)).try_init(),
+    };
+    installed.map_err(|err| ConfigError::Invalid(format!("could not set up logging: {err}")))?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Filters by a new `log_level` from here on
+pub fn set_level(level: &str) -> Result<(), ConfigError> {
+    let Some(handle) = FILTER.get() else {
+        return Ok(());
+    };
+    handle
+        .reload(filter(level)?)
+        .map_err(|err| ConfigError::Invalid(format!("could not change log_level: {err}")))
+}
+
+fn filter(level: &str) -> Result<EnvFilter, ConfigError> {
+    EnvFilter::try_new(level)
+        .map_err(|err| ConfigError::Invalid(format!("log_level {level:?}: {err}")))
+}
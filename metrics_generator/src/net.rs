@@ -0,0 +1,165 @@
+use crate::config::Interface;
+use crate::load::LOAD_FREQ;
+use rand::Rng;
+
+// average packet size, most traffic is a mix of full frames and small acks
+const PACKET_SIZE: f64 = 800.0;
+// share of packets that arrive broken, and that are dropped on a calm box
+const ERROR_RATE: f64 = 0.000_001;
+const DROP_RATE: f64 = 0.000_01;
+
+/// Counters of one interface since the box came up, like /proc/net/dev
+#[derive(Default, Clone, Copy)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+pub struct Link {
+    pub interface: Interface,
+    pub counters: Counters,
+}
+
+/// Simulates the traffic on the network interfaces of a box. Traffic follows
+/// the CPU load, and a box with more work than cores starts dropping packets.
+pub struct NetModel {
+    links: Vec<Link>,
+}
+
+impl NetModel {
+    pub fn new(interfaces: &[Interface]) -> NetModel {
+        let links = interfaces
+            .iter()
+            .map(|interface| Link {
+                interface: interface.clone(),
+                counters: Counters::default(),
+            })
+            .collect();
+
+        NetModel { links }
+    }
+
+    /// Moves the counters on by that many ticks at `load_share`, all of them
+    /// in one go like the disks
+    pub fn advance(&mut self, rng: &mut impl Rng, ticks: u64, load_share: f64) {
+        if ticks == 0 {
+            return;
+        }
+        let secs = ticks as f64 * LOAD_FREQ.as_secs_f64();
+        for link in &mut self.links {
+            link.tick(rng, secs, load_share);
+        }
+    }
+
+    /// Starts all counters over, as a reboot would
+    pub fn reset(&mut self) {
+        for link in &mut self.links {
+            link.counters = Counters::default();
+        }
+    }
+
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+}
+
+impl Link {
+    fn tick(&mut self, rng: &mut impl Rng, secs: f64, load_share: f64) {
+        let busy = 0.5 + load_share;
+        // past all cores busy the softirqs fall behind and the backlog overflows
+        let drop_rate = DROP_RATE * (1.0 + 100.0 * (load_share - 1.0).max(0.0));
+
+        let rx_bytes =
+            self.interface.rx_bytes_per_sec as f64 * busy * rng.gen_range(0.7..1.3) * secs;
+        let tx_bytes =
+            self.interface.tx_bytes_per_sec as f64 * busy * rng.gen_range(0.7..1.3) * secs;
+        let rx_packets = rx_bytes / PACKET_SIZE;
+        let tx_packets = tx_bytes / PACKET_SIZE;
+
+        let counters = &mut self.counters;
+        counters.rx_bytes += rx_bytes as u64;
+        counters.tx_bytes += tx_bytes as u64;
+        counters.rx_packets += rx_packets as u64;
+        counters.tx_packets += tx_packets as u64;
+        counters.rx_errors += occurrences(rng, rx_packets * ERROR_RATE);
+        counters.tx_errors += occurrences(rng, tx_packets * ERROR_RATE);
+        counters.rx_dropped += occurrences(rng, rx_packets * drop_rate);
+        counters.tx_dropped += occurrences(rng, tx_packets * drop_rate);
+    }
+}
+
+// rounds randomly so rare events still show up now and then, instead of
+// always rounding down to none
+fn occurrences(rng: &mut impl Rng, expected: f64) -> u64 {
+    (expected + rng.gen::<f64>()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn counters(net: &NetModel) -> [u64; 8] {
+        let c = net.links()[0].counters;
+        [
+            c.rx_bytes,
+            c.tx_bytes,
+            c.rx_packets,
+            c.tx_packets,
+            c.rx_errors,
+            c.tx_errors,
+            c.rx_dropped,
+            c.tx_dropped,
+        ]
+    }
+
+    fn model() -> NetModel {
+        NetModel::new(&[Interface {
+            device: "eth0".into(),
+            rx_bytes_per_sec: 50 << 20,
+            tx_bytes_per_sec: 20 << 20,
+        }])
+    }
+
+    #[test]
+    fn counters_only_go_up() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut net = model();
+        let mut before = counters(&net);
+        for tick in 0..500 {
+            // calm and overloaded, where packets start getting dropped
+            let load_share = if tick % 100 < 50 { 0.3 } else { 3.0 };
+            net.advance(&mut rng, 1, load_share);
+            let after = counters(&net);
+            assert!(before.iter().zip(&after).all(|(b, a)| a >= b), "{after:?}");
+            before = after;
+        }
+        assert!(before.iter().all(|&count| count > 0), "{before:?}");
+
+        // ticks caught up in one go count as many
+        let mut catch_up = model();
+        catch_up.advance(&mut rng, 10, 0.3);
+        assert!(counters(&catch_up)[0] > 9 * 5 * (50 << 20) / 2);
+    }
+
+    #[test]
+    fn restart_starts_the_counters_over() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut net = model();
+        net.advance(&mut rng, 100, 2.0);
+        assert!(counters(&net)[0] > 0);
+
+        net.reset();
+        assert_eq!(counters(&net), [0; 8]);
+        // and they count up from there as before
+        net.advance(&mut rng, 1, 0.5);
+        assert!(counters(&net)[0] > 0);
+    }
+}
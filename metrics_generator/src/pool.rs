@@ -0,0 +1,112 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed size pool of worker threads. Keeps track of how many workers are busy
+/// so callers can turn clients away instead of queueing them behind a stalled
+/// connection.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+    busy: Arc<AtomicUsize>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&busy)))
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            busy,
+        }
+    }
+
+    /// Claims an idle worker for a job that is about to be handed over with
+    /// `execute`. False if every worker is already busy.
+    pub fn try_reserve(&self) -> bool {
+        self.busy
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |busy| {
+                (busy < self.workers.len()).then_some(busy + 1)
+            })
+            .is_ok()
+    }
+
+    /// Hands the job to the worker claimed with `try_reserve`
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .as_ref()
+            .expect("pool is running")
+            .send(Box::new(f))
+            .expect("workers are alive while the pool is");
+    }
+
+    /// Waits for the workers to finish what they are on, for at most
+    /// `timeout`. Returns how many were still busy when it ran out.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let busy = self.busy.load(Ordering::SeqCst);
+            if busy == 0 || Instant::now() >= deadline {
+                return busy;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel makes every worker exit its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, busy: Arc<AtomicUsize>) -> Worker {
+        let thread = thread::spawn(move || loop {
+            let message = receiver.lock().unwrap().recv();
+
+            match message {
+                Ok(job) => {
+                    // keep the worker alive even if a single connection blows up
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!(worker = id, "recovered from a panicked job");
+                    }
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(_) => break,
+            }
+        });
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::generator::{MetricsCpu, MetricsMem};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+/// The processors of the machine as /proc/cpuinfo lists them
+pub struct CpuInfo {
+    /// physical cores, counted once however many threads they run
+    pub cores: u32,
+    /// logical processors, what the kernel schedules on
+    pub threads: u32,
+}
+
+/// The load averages of the machine, with its processor count as the
+/// thread count
+pub fn cpu() -> io::Result<MetricsCpu> {
+    let [load_1m, load_5m, load_15m] = parse_loadavg(&fs::read_to_string("/proc/loadavg")?)?;
+    Ok(MetricsCpu {
+        load_1m,
+        load_5m,
+        load_15m,
+        thread_count: cpuinfo()?.threads,
+    })
+}
+
+/// Memory in use the way `free` counts it, all of it but what is available
+/// to start new programs without swapping
+pub fn memory() -> io::Result<MetricsMem> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo")?)
+}
+
+pub fn cpuinfo() -> io::Result<CpuInfo> {
+    parse_cpuinfo(&fs::read_to_string("/proc/cpuinfo")?)
+}
+
+// "0.23 0.31 0.24 2/72 20659", the last two are the run queue and last pid
+fn parse_loadavg(loadavg: &str) -> io::Result<[f64; 3]> {
+    let mut averages = loadavg.split_whitespace().map(str::parse::<f64>);
+    let mut next = || {
+        averages
+            .next()
+            .and_then(Result::ok)
+            .ok_or_else(|| invalid("/proc/loadavg", "load averages"))
+    };
+    Ok([next()?, next()?, next()?])
+}
+
+fn parse_meminfo(meminfo: &str) -> io::Result<MetricsMem> {
+    let total_bytes = meminfo_bytes(meminfo, "MemTotal")?;
+    // kernels before 3.14 don't estimate it, free and the page cache is
+    // what older versions of free took for available
+    let available = meminfo_bytes(meminfo, "MemAvailable").or_else(|_| {
+        ["MemFree", "Buffers", "Cached"]
+            .iter()
+            .map(|key| meminfo_bytes(meminfo, key))
+            .sum::<io::Result<u64>>()
+    })?;
+    Ok(MetricsMem {
+        used_bytes: total_bytes.saturating_sub(available),
+        total_bytes,
+    })
+}
+
+fn parse_cpuinfo(cpuinfo: &str) -> io::Result<CpuInfo> {
+    let mut threads = 0;
+    // a core is a core id within a physical package, threads of the same
+    // core share the pair
+    let mut cores = HashSet::new();
+    for block in cpuinfo.split("\n\n") {
+        let field = |name: &str| {
+            block.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim())
+            })
+        };
+        if field("processor").is_none() {
+            continue;
+        }
+        threads += 1;
+        if let (Some(package), Some(core)) = (field("physical id"), field("core id")) {
+            cores.insert((package, core));
+        }
+    }
+
+    if threads == 0 {
+        return Err(invalid("/proc/cpuinfo", "processors"));
+    }
+    // ARM and some virtual machines don't say which core a processor is on
+    let cores = if cores.is_empty() {
+        threads
+    } else {
+        cores.len() as u32
+    };
+    Ok(CpuInfo { cores, threads })
+}
+
+// a line such as "MemTotal:        6158152 kB"
+fn meminfo_bytes(meminfo: &str, key: &str) -> io::Result<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim().strip_suffix("kB")?.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| invalid("/proc/meminfo", key))
+}
+
+fn invalid(path: &str, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no {what} found in {path}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two sockets of two cores with two threads each, core ids repeating
+    // across the sockets like they do
+    const TWO_SOCKETS: &str = "\
+processor\t: 0
+physical id\t: 0
+core id\t\t: 0
+
+processor\t: 1
+physical id\t: 0
+core id\t\t: 1
+
+processor\t: 2
+physical id\t: 1
+core id\t\t: 0
+
+processor\t: 3
+physical id\t: 1
+core id\t\t: 1
+
+processor\t: 4
+physical id\t: 0
+core id\t\t: 0
+
+processor\t: 5
+physical id\t: 0
+core id\t\t: 1
+
+processor\t: 6
+physical id\t: 1
+core id\t\t: 0
+
+processor\t: 7
+physical id\t: 1
+core id\t\t: 1
+";
+
+    #[test]
+    fn parses_load_averages() {
+        let averages = parse_loadavg("0.23 0.31 0.24 2/72 20659\n").unwrap();
+        assert_eq!(averages, [0.23, 0.31, 0.24]);
+        assert!(parse_loadavg("0.23 0.31\n").is_err());
+        assert!(parse_loadavg("0.23 x 0.24 2/72 20659\n").is_err());
+    }
+
+    #[test]
+    fn counts_cores_per_socket() {
+        let info = parse_cpuinfo(TWO_SOCKETS).unwrap();
+        assert_eq!((info.cores, info.threads), (4, 8));
+
+        // no core ids to go by, as on ARM
+        let arm = "processor\t: 0\nBogoMIPS\t: 48.00\n\nprocessor\t: 1\nBogoMIPS\t: 48.00\n";
+        let info = parse_cpuinfo(arm).unwrap();
+        assert_eq!((info.cores, info.threads), (2, 2));
+
+        assert!(parse_cpuinfo("").is_err());
+    }
+
+    #[test]
+    fn takes_used_memory_from_available() {
+        let meminfo = "\
+MemTotal:        8000000 kB
+MemFree:         1000000 kB
+MemAvailable:    5000000 kB
+Buffers:          200000 kB
+Cached:          3000000 kB
+";
+        let memory = parse_meminfo(meminfo).unwrap();
+        assert_eq!(memory.total_bytes, 8_000_000 * 1024);
+        assert_eq!(memory.used_bytes, 3_000_000 * 1024);
+
+        // an old kernel without MemAvailable
+        let old = meminfo.replace("MemAvailable:    5000000 kB\n", "");
+        let memory = parse_meminfo(&old).unwrap();
+        assert_eq!(memory.used_bytes, 3_800_000 * 1024);
+
+        // without the page cache either there is nothing to go by
+        let err = parse_meminfo(&old.replace("Cached:", "Kached:"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Cached"), "{err}");
+        assert!(parse_meminfo("MemFree: 1 kB\n").is_err());
+    }
+}
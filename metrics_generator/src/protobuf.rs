@@ -0,0 +1,244 @@
+use crate::histogram::NativeBuckets;
+use crate::snapshot::{Family, Kind, Value};
+use prost::Message;
+
+/// What Prometheus asks for when it wants native histograms
+pub const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+// the messages of io.prometheus.client (prometheus/client_model metrics.proto)
+// that the generator uses, with the same tags
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricFamily {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    help: String,
+    #[prost(enumeration = "MetricType", tag = "3")]
+    r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    metric: Vec<Metric>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+enum MetricType {
+    Counter = 0,
+    Gauge = 1,
+    Summary = 2,
+    Histogram = 4,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    counter: Option<Counter>,
+    #[prost(message, optional, tag = "4")]
+    summary: Option<Summary>,
+    #[prost(message, optional, tag = "7")]
+    histogram: Option<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LabelPair {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Gauge {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Counter {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Summary {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    quantile: Vec<Quantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Quantile {
+    #[prost(double, tag = "1")]
+    quantile: f64,
+    #[prost(double, tag = "2")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    /// the classic buckets, cumulative
+    #[prost(message, repeated, tag = "3")]
+    bucket: Vec<Bucket>,
+    #[prost(sint32, tag = "5")]
+    schema: i32,
+    #[prost(double, tag = "6")]
+    zero_threshold: f64,
+    #[prost(uint64, tag = "7")]
+    zero_count: u64,
+    #[prost(message, repeated, tag = "12")]
+    positive_span: Vec<BucketSpan>,
+    /// each count as the difference to the one before
+    #[prost(sint64, repeated, tag = "13")]
+    positive_delta: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Bucket {
+    #[prost(uint64, tag = "1")]
+    cumulative_count: u64,
+    #[prost(double, tag = "2")]
+    upper_bound: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BucketSpan {
+    /// gap to the end of the previous span, or the first index for the first
+    #[prost(sint32, tag = "1")]
+    offset: i32,
+    #[prost(uint32, tag = "2")]
+    length: u32,
+}
+
+/// Renders the families as length delimited io.prometheus.client.MetricFamily
+/// messages, histograms with both their classic and native buckets
+pub fn encode(families: &[Family]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for family in families {
+        let message = metric_family(family);
+        // writing into a Vec can't run out of room
+        message
+            .encode_length_delimited(&mut buffer)
+            .expect("buffer grows as needed");
+    }
+    buffer
+}
+
+fn metric_family(family: &Family) -> MetricFamily {
+    let (name, metric_type) = match family.kind {
+        // the text encoder adds the suffix for counters, here it is part of the name
+        Kind::Counter => (format!("{}_total", family.name), MetricType::Counter),
+        Kind::Gauge => (family.name.clone(), MetricType::Gauge),
+        Kind::Histogram => (family.name.clone(), MetricType::Histogram),
+        Kind::Summary => (family.name.clone(), MetricType::Summary),
+    };
+
+    let metric = family
+        .samples
+        .iter()
+        .map(|sample| {
+            let mut metric = Metric {
+                label: sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| LabelPair {
+                        name: name.to_string(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+                ..Metric::default()
+            };
+            match &sample.value {
+                Value::Counter(value) => metric.counter = Some(Counter { value: *value }),
+                Value::Gauge(value) => metric.gauge = Some(Gauge { value: *value }),
+                Value::Summary(totals) => {
+                    metric.summary = Some(Summary {
+                        sample_count: totals.count,
+                        sample_sum: totals.sum,
+                        quantile: totals
+                            .quantiles
+                            .iter()
+                            .map(|&(quantile, value)| Quantile { quantile, value })
+                            .collect(),
+                    })
+                }
+                Value::Histogram(totals) => {
+                    let mut cumulative = 0;
+                    let mut histogram = Histogram {
+                        sample_count: totals.count,
+                        sample_sum: totals.sum,
+                        // the +Inf bucket is implied by the count
+                        bucket: totals
+                            .buckets
+                            .iter()
+                            .filter(|(bound, _)| *bound != f64::MAX)
+                            .map(|&(upper_bound, count)| {
+                                cumulative += count;
+                                Bucket {
+                                    cumulative_count: cumulative,
+                                    upper_bound,
+                                }
+                            })
+                            .collect(),
+                        ..Histogram::default()
+                    };
+                    if let Some(native) = &totals.native {
+                        add_native_buckets(&mut histogram, native);
+                    }
+                    metric.histogram = Some(histogram);
+                }
+            }
+            metric
+        })
+        .collect();
+
+    MetricFamily {
+        name,
+        help: family.help.clone(),
+        r#type: metric_type as i32,
+        metric,
+    }
+}
+
+fn add_native_buckets(histogram: &mut Histogram, native: &NativeBuckets) {
+    histogram.schema = native.schema;
+    // a zero threshold above 0 is also what marks the histogram as native when
+    // it has no buckets yet
+    histogram.zero_threshold = native.zero_threshold;
+    histogram.zero_count = native.zero_count;
+
+    let mut previous: Option<(i32, u64)> = None;
+    for (&index, &count) in &native.positive {
+        match previous {
+            // right after the last bucket, the span goes on
+            Some((last, _)) if index == last + 1 => {
+                if let Some(span) = histogram.positive_span.last_mut() {
+                    span.length += 1;
+                }
+            }
+            Some((last, _)) => histogram.positive_span.push(BucketSpan {
+                offset: index - last - 1,
+                length: 1,
+            }),
+            None => histogram.positive_span.push(BucketSpan {
+                offset: index,
+                length: 1,
+            }),
+        }
+
+        let before = previous.map_or(0, |(_, count)| count);
+        histogram.positive_delta.push(count as i64 - before as i64);
+        previous = Some((index, count));
+    }
+}
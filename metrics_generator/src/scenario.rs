@@ -0,0 +1,236 @@
+use crate::config::ConfigError;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// A scripted timeline of phases the simulated server goes through, e.g. to
+/// rehearse alerts against a memory leak followed by an outage. Read from a
+/// TOML file, see scenarios/ for examples.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// start over once the last phase is done, instead of going back to
+    /// unscripted behaviour
+    #[serde(default)]
+    pub repeat: bool,
+    #[serde(rename = "phase")]
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    pub name: String,
+    pub duration_secs: u64,
+    /// run queue length as a share of the cores, 1.0 keeps every core busy
+    pub cpu: Option<f64>,
+    /// share of the memory in use, ramped linearly towards `memory_to` over
+    /// the phase when that is given
+    pub memory: Option<f64>,
+    pub memory_to: Option<f64>,
+    #[serde(default)]
+    pub health: Health,
+    /// how long each up and down lasts while flapping
+    #[serde(default = "default_flap_secs")]
+    pub flap_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    /// the usual occasional unhealthy response
+    #[default]
+    Random,
+    Healthy,
+    Unhealthy,
+    /// alternates between healthy and unhealthy every `flap_secs`
+    Flapping,
+    /// no endpoint answers at all
+    Outage,
+}
+
+fn default_flap_secs() -> u64 {
+    30
+}
+
+impl Scenario {
+    pub fn from_file(path: &Path) -> Result<Scenario, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let scenario: Scenario =
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        scenario
+            .validate()
+            .map_err(|msg| ConfigError::Invalid(format!("scenario {}: {msg}", path.display())))?;
+        Ok(scenario)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.phases.is_empty() {
+            return Err("needs at least one [[phase]]".into());
+        }
+
+        for phase in &self.phases {
+            let name = &phase.name;
+            if phase.duration_secs == 0 {
+                return Err(format!("phase {name:?}: duration_secs must be at least 1"));
+            }
+            if phase.cpu.is_some_and(|cpu| !(0.0..=100.0).contains(&cpu)) {
+                return Err(format!("phase {name:?}: cpu must be between 0 and 100"));
+            }
+            for share in [phase.memory, phase.memory_to].into_iter().flatten() {
+                if !(0.0..=1.0).contains(&share) {
+                    return Err(format!(
+                        "phase {name:?}: memory and memory_to must be between 0 and 1"
+                    ));
+                }
+            }
+            if phase.memory_to.is_some() && phase.memory.is_none() {
+                return Err(format!(
+                    "phase {name:?}: memory_to needs memory to ramp from"
+                ));
+            }
+            if phase.flap_secs == 0 {
+                return Err(format!("phase {name:?}: flap_secs must be at least 1"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The phase running at `elapsed` into the scenario, with its index and how
+    /// far into the phase that is. None once a non repeating scenario is over.
+    pub fn phase_at(&self, elapsed: Duration) -> Option<(usize, &Phase, Duration)> {
+        let total: u64 = self.phases.iter().map(|p| p.duration_secs).sum();
+        let mut offset = elapsed.as_secs_f64();
+        if self.repeat {
+            offset %= total as f64;
+        }
+
+        for (index, phase) in self.phases.iter().enumerate() {
+            let duration = phase.duration_secs as f64;
+            if offset < duration {
+                return Some((index, phase, Duration::from_secs_f64(offset)));
+            }
+            offset -= duration;
+        }
+        None
+    }
+}
+
+impl Phase {
+    /// Share of the memory in use at `into` the phase, if the phase sets it
+    pub fn memory_share(&self, into: Duration) -> Option<f64> {
+        let from = self.memory?;
+        let to = self.memory_to.unwrap_or(from);
+        let progress = (into.as_secs_f64() / self.duration_secs as f64).min(1.0);
+        Some(from + (to - from) * progress)
+    }
+
+    /// Whether the server is healthy at `into` the phase, None leaves it to chance
+    pub fn healthy(&self, into: Duration) -> Option<bool> {
+        match self.health {
+            Health::Random => None,
+            Health::Healthy => Some(true),
+            Health::Unhealthy | Health::Outage => Some(false),
+            // start off with the up half of the cycle
+            Health::Flapping => Some((into.as_secs() / self.flap_secs).is_multiple_of(2)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(repeat: bool) -> Scenario {
+        toml::from_str(&format!(
+            r#"
+            repeat = {repeat}
+
+            [[phase]]
+            name = "warm up"
+            duration_secs = 60
+
+            [[phase]]
+            name = "leak"
+            duration_secs = 120
+            memory = 0.5
+            memory_to = 0.9
+
+            [[phase]]
+            name = "flap"
+            duration_secs = 30
+            health = "flapping"
+            flap_secs = 10
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn at(scenario: &Scenario, secs: f64) -> Option<(usize, f64)> {
+        scenario
+            .phase_at(Duration::from_secs_f64(secs))
+            .map(|(index, _, into)| (index, into.as_secs_f64()))
+    }
+
+    #[test]
+    fn finds_the_phase_running() {
+        let scenario = scenario(false);
+        assert!(scenario.validate().is_ok());
+        assert_eq!(at(&scenario, 0.0), Some((0, 0.0)));
+        assert_eq!(at(&scenario, 59.5), Some((0, 59.5)));
+        // a phase starts the moment the one before it is over
+        assert_eq!(at(&scenario, 60.0), Some((1, 0.0)));
+        assert_eq!(at(&scenario, 200.0), Some((2, 20.0)));
+        assert_eq!(at(&scenario, 210.0), None);
+        assert_eq!(at(&scenario, 1000.0), None);
+    }
+
+    #[test]
+    fn wraps_around_when_repeating() {
+        let scenario = scenario(true);
+        assert_eq!(at(&scenario, 210.0), Some((0, 0.0)));
+        assert_eq!(at(&scenario, 275.0), Some((1, 5.0)));
+        // many rounds in
+        assert_eq!(at(&scenario, 210.0 * 100.0 + 205.0), Some((2, 25.0)));
+    }
+
+    #[test]
+    fn ramps_memory_and_flaps_over_a_phase() {
+        let scenario = scenario(false);
+        let [warm_up, leak, flap] = &scenario.phases[..] else {
+            panic!("three phases expected");
+        };
+        assert_eq!(warm_up.memory_share(Duration::ZERO), None);
+        assert_eq!(leak.memory_share(Duration::ZERO), Some(0.5));
+        let halfway = leak.memory_share(Duration::from_secs(60)).unwrap();
+        assert!((halfway - 0.7).abs() < 1e-9, "{halfway}");
+        assert_eq!(leak.memory_share(Duration::from_secs(500)), Some(0.9));
+
+        assert_eq!(warm_up.healthy(Duration::ZERO), None);
+        let health: Vec<_> = [0, 9, 10, 19, 20]
+            .map(|secs| flap.healthy(Duration::from_secs(secs)))
+            .into();
+        assert_eq!(
+            health,
+            [Some(true), Some(true), Some(false), Some(false), Some(true)]
+        );
+    }
+
+    #[test]
+    fn refuses_broken_phases() {
+        for broken in [
+            "phase = []",
+            "[[phase]]\nname = \"a\"\nduration_secs = 0",
+            "[[phase]]\nname = \"a\"\nduration_secs = 1\ncpu = -1.0",
+            "[[phase]]\nname = \"a\"\nduration_secs = 1\nmemory_to = 0.5",
+            "[[phase]]\nname = \"a\"\nduration_secs = 1\nflap_secs = 0",
+        ] {
+            let scenario: Scenario = toml::from_str(broken).unwrap();
+            assert!(scenario.validate().is_err(), "{broken}");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::fleet::{Fleet, Host};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+// a target group in the format both Prometheus file_sd_configs and
+// http_sd_configs read
+#[derive(Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<&'static str, String>,
+}
+
+impl TargetGroup {
+    // no host label, the metrics carry one already and Prometheus would
+    // rename theirs to exported_host
+    fn new(host: &Host, target: String) -> TargetGroup {
+        let mut labels = BTreeMap::new();
+        labels.insert("profile", host.profile_name.clone());
+        if let Some(datacenter) = &host.datacenter {
+            labels.insert("datacenter", datacenter.clone());
+        }
+        if let Some(role) = &host.role {
+            labels.insert("role", role.clone());
+        }
+
+        TargetGroup {
+            targets: vec![target],
+            labels,
+        }
+    }
+}
+
+// host:port, with IPv6 addresses in brackets
+fn target(ip: IpAddr, port: u16) -> String {
+    SocketAddr::new(ip, port).to_string()
+}
+
+/// Every simulated host as an http_sd_configs target. Hosts without a
+/// listener of their own are scraped through the main one on `port`.
+pub fn http_sd(fleet: &Fleet, ip: IpAddr, port: u16) -> serde_json::Result<String> {
+    let groups: Vec<TargetGroup> = fleet
+        .hosts()
+        .iter()
+        .map(|host| match host.port {
+            Some(port) => TargetGroup::new(host, target(ip, port)),
+            None => {
+                let mut group = TargetGroup::new(host, target(ip, port));
+                group
+                    .labels
+                    .insert("__metrics_path__", format!("/hosts/{}/metrics", host.id));
+                group
+            }
+        })
+        .collect();
+
+    serde_json::to_string(&groups)
+}
+
+/// Writes the per host listeners out as a file_sd target list. Written to a
+/// temporary file first and moved in place, as Prometheus watches the file and
+/// could otherwise pick up half of it.
+pub fn write_file_sd(path: &Path, fleet: &Fleet, ip: IpAddr) -> io::Result<()> {
+    let groups: Vec<TargetGroup> = fleet
+        .hosts()
+        .iter()
+        .filter_map(|host| {
+            host.port
+                .map(|port| TargetGroup::new(host, target(ip, port)))
+        })
+        .collect();
+
+    let content = serde_json::to_string_pretty(&groups)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
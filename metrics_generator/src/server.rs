@@ -0,0 +1,941 @@
+use crate::auth::Auth;
+use crate::compress;
+use crate::config::{Config, ConfigError, HostConfig, HostRequest};
+use crate::error::{Error, Result};
+use crate::exporter::Format;
+use crate::fleet::{Fleet, Host};
+use crate::http::{self, Request, Response, Version};
+use crate::pool::ThreadPool;
+use crate::sd;
+use crate::source::HostSnapshot;
+use crate::telemetry::Telemetry;
+use crate::tls;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::SockRef;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, trace, warn};
+
+// everything the request handlers share
+pub struct State {
+    pub fleet: Fleet,
+    pub telemetry: Arc<Telemetry>,
+    // swapped out as a whole on reload, requests hold on to the one they
+    // started with
+    settings: RwLock<Arc<Settings>>,
+    shutting_down: AtomicBool,
+}
+
+/// What a reload of the configuration can change
+pub struct Settings {
+    pub config: Config,
+    /// set when the listeners speak HTTPS
+    pub tls: Option<Arc<ServerConfig>>,
+    pub auth: Auth,
+}
+
+impl Settings {
+    pub fn new(config: Config) -> std::result::Result<Settings, ConfigError> {
+        let tls = tls::server_config(&config)?;
+        let auth = Auth::new(&config.auth)?;
+        Ok(Settings { config, tls, auth })
+    }
+
+    /// Settings for a reload. A self-signed certificate is made once per
+    /// start, so as long as the TLS settings stay the same the running one
+    /// is kept rather than generated and written out again
+    pub fn reload(
+        config: Config,
+        running: &Settings,
+    ) -> std::result::Result<Settings, ConfigError> {
+        let unchanged = config.tls_self_signed
+            && running.config.tls_self_signed
+            && config.tls_cert_path == running.config.tls_cert_path
+            && config.tls_key_path == running.config.tls_key_path
+            && config.tls_client_ca_path == running.config.tls_client_ca_path;
+        if !unchanged {
+            return Settings::new(config);
+        }
+        let auth = Auth::new(&config.auth)?;
+        Ok(Settings {
+            config,
+            tls: running.tls.clone(),
+            auth,
+        })
+    }
+}
+
+impl State {
+    pub fn new(fleet: Fleet, telemetry: Arc<Telemetry>, settings: Settings) -> State {
+        State {
+            fleet,
+            telemetry,
+            settings: RwLock::new(Arc::new(settings)),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Serves every request from here on with the new settings
+    pub fn reload(&self, settings: Settings) {
+        *self
+            .settings
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(settings);
+    }
+
+    /// Has the listeners stop accepting and connections close after the
+    /// request they are on
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+/// What a listener speaks for
+#[derive(Clone)]
+pub enum Scope {
+    /// the whole fleet, each host under /hosts/<id>/
+    Fleet,
+    /// a single host, as if it were a server of its own
+    Host(String),
+}
+
+/// A socket connections come in on
+pub enum Listener {
+    Tcp(TcpListener),
+    /// always plain HTTP, the file permissions of the socket decide who gets in
+    Unix(UnixListener),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Another handle on the same socket, to stop it from another thread
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    /// Wakes up a blocked accept and fails every one after it. Closing the
+    /// socket wouldn't do, Linux leaves a thread blocked in accept there.
+    pub fn stop(&self) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => SockRef::from(listener).shutdown(Shutdown::Read),
+            Listener::Unix(listener) => SockRef::from(listener).shutdown(Shutdown::Read),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
+
+impl Connection {
+    // another handle on the socket, to change its timeouts while a reader
+    // holds the stream
+    fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(Some(timeout)),
+            Connection::Unix(stream) => stream.set_read_timeout(Some(timeout)),
+        }
+    }
+
+    // don't let a client that never sends or reads tie up a worker forever
+    fn set_timeouts(&self, config: &Config) -> io::Result<()> {
+        let read = Some(Duration::from_millis(config.read_timeout_ms));
+        let write = Some(Duration::from_millis(config.write_timeout_ms));
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(read)?;
+                stream.set_write_timeout(write)
+            }
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(read)?;
+                stream.set_write_timeout(write)
+            }
+        }
+    }
+
+    // who is on the other end, for the logs
+    fn peer(&self) -> String {
+        match self {
+            Connection::Tcp(stream) => stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string()),
+            // the client end of a Unix socket has no name
+            Connection::Unix(_) => "unix".to_string(),
+        }
+    }
+
+    fn is_tls(&self, settings: &Settings) -> bool {
+        matches!(self, Connection::Tcp(_)) && settings.tls.is_some()
+    }
+}
+
+/// Accepts connections until shutting down, handing them to the pool
+pub fn listen(listener: Listener, scope: Scope, state: Arc<State>, pool: Arc<ThreadPool>) {
+    loop {
+        let stream = listener.accept();
+        if state.shutting_down() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept connection: {err}");
+                continue;
+            }
+        };
+        let peer = stream.peer();
+        debug!(%peer, "connection established");
+
+        let settings = state.settings();
+        let config = &settings.config;
+        if let Err(err) = stream.set_timeouts(config) {
+            warn!(%peer, "failed to set socket timeouts: {err}");
+            continue;
+        }
+
+        if !pool.try_reserve() {
+            warn!(
+                %peer,
+                workers = config.max_connections,
+                "all workers busy, rejecting connection"
+            );
+            // a 503 would need a handshake first, which is what the accept
+            // loop must not wait on, so TLS clients just see the connection close
+            if stream.is_tls(&settings) {
+                continue;
+            }
+            let busy = Response::new(503).with_header("Retry-After", "1");
+            let _ = match stream {
+                Connection::Tcp(mut stream) => {
+                    busy.write_to(&mut stream, Version::Http11, false, false)
+                }
+                Connection::Unix(mut stream) => {
+                    busy.write_to(&mut stream, Version::Http11, false, false)
+                }
+            };
+            continue;
+        }
+
+        let state = Arc::clone(&state);
+        let scope = scope.clone();
+        pool.execute(move || handle_connection(stream, &peer, &state, &scope));
+    }
+}
+
+fn handle_connection(stream: Connection, peer: &str, state: &State, scope: &Scope) {
+    let _open = state.telemetry.open_connection();
+    let socket = match stream.try_clone() {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!(%peer, "failed to set up connection: {err}");
+            return;
+        }
+    };
+    let socket = Some(&socket);
+    match (stream, &state.settings().tls) {
+        // the handshake happens on the first read
+        (Connection::Tcp(stream), Some(tls)) => match ServerConnection::new(Arc::clone(tls)) {
+            Ok(connection) => handle_stream(
+                StreamOwned::new(connection, stream),
+                socket,
+                peer,
+                state,
+                scope,
+            ),
+            Err(err) => warn!(%peer, "failed to set up TLS: {err}"),
+        },
+        (Connection::Tcp(stream), None) => handle_stream(stream, socket, peer, state, scope),
+        (Connection::Unix(stream), _) => handle_stream(stream, socket, peer, state, scope),
+    }
+}
+
+// `socket` is the connection underneath the stream, to wait on idle ones for
+// a shorter while
+fn handle_stream(
+    stream: impl Read + Write,
+    socket: Option<&Connection>,
+    peer: &str,
+    state: &State,
+    scope: &Scope,
+) {
+    let mut reader = BufReader::new(stream);
+    if let Err(err) = serve(&mut reader, socket, peer, state, scope) {
+        // clients hanging up or going quiet are nothing out of the ordinary
+        if matches!(err, Error::Io(_)) {
+            debug!(%peer, "failed to serve request: {err}");
+        } else {
+            warn!(%peer, "failed to serve request: {err}");
+        }
+        if let Some(status) = err.status() {
+            // best effort, the client may well be gone already
+            let _ = Response::new(status).write_to(reader.get_mut(), Version::Http11, false, false);
+        }
+    }
+}
+
+// serves requests off the connection until either side wants to close it
+fn serve<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    socket: Option<&Connection>,
+    peer: &str,
+    state: &State,
+    scope: &Scope,
+) -> Result<()> {
+    let mut first = true;
+    while first || wait_for_request(reader, socket, state)? {
+        first = false;
+        let Some(request) = http::read_request(reader)? else {
+            break;
+        };
+        let started = Instant::now();
+        trace!(%peer, headers = ?redacted(&request.headers), "request headers");
+        // a connection kept open would hold up the shutdown
+        let keep_alive = request.keep_alive() && !state.shutting_down();
+        let head_only = request.method == "HEAD";
+
+        let routed = route(&request, state, scope);
+        let (status, bytes) = match &routed {
+            // reads only ever go through the buffer, writes straight to the stream
+            Ok(Some(response)) => {
+                response.write_to(reader.get_mut(), request.version, head_only, keep_alive)?;
+                let bytes = if head_only { 0 } else { response.body.len() };
+                (Some(response.status), bytes)
+            }
+            // nothing to say, hang up
+            Ok(None) => (None, 0),
+            // answered with the status by handle_stream
+            Err(err) => (err.status(), 0),
+        };
+        let elapsed = started.elapsed();
+        access_log(&request, peer, status, bytes, elapsed);
+        let path = route_pattern(scope, &request.path);
+        state.telemetry.observe_request(path, status, elapsed);
+
+        if routed?.is_none() || !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// waits for the next request on a kept alive connection for idle_timeout_ms
+// only, so a scraper coming back later doesn't hold a worker until then.
+// False when the client went quiet or hung up.
+fn wait_for_request<S: Read>(
+    reader: &mut BufReader<S>,
+    socket: Option<&Connection>,
+    state: &State,
+) -> Result<bool> {
+    let Some(socket) = socket else {
+        return Ok(true);
+    };
+    let config = &state.settings().config;
+    socket.set_read_timeout(Duration::from_millis(config.idle_timeout_ms))?;
+    let waiting = match reader.fill_buf() {
+        Ok(buffered) => !buffered.is_empty(),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            false
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // the request itself gets the full read timeout again
+    socket.set_read_timeout(Duration::from_millis(config.read_timeout_ms))?;
+    Ok(waiting)
+}
+
+// One line per request under the access target, so they can be turned off
+// with access=off. No status means the connection was dropped unanswered.
+fn access_log(request: &Request, peer: &str, status: Option<u16>, bytes: usize, elapsed: Duration) {
+    info!(
+        target: "access",
+        method = %request.method,
+        path = %request.path,
+        status,
+        bytes,
+        // to the microsecond, f64 noise past that just makes the line longer
+        duration_ms = (elapsed.as_secs_f64() * 1e6).round() / 1e3,
+        peer,
+    );
+}
+
+// the route a path takes, with the host id left out
+fn route_pattern(scope: &Scope, path: &str) -> &'static str {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    match (scope, segments.as_slice()) {
+        (_, ["healthz"]) => "/healthz",
+        (_, ["stats"]) => "/stats",
+        (_, ["metrics"]) => "/metrics",
+        (Scope::Fleet, ["metrics", "generator"]) => "/metrics/generator",
+        (Scope::Fleet, ["sd"]) => "/sd",
+        (Scope::Fleet, ["hosts", _]) => "/hosts/{id}",
+        (Scope::Fleet, ["hosts", _, "healthz"]) => "/hosts/{id}/healthz",
+        (Scope::Fleet, ["hosts", _, "stats"]) => "/hosts/{id}/stats",
+        (Scope::Fleet, ["hosts", _, "metrics"]) => "/hosts/{id}/metrics",
+        // anything else is a 404, and could be anything at all
+        _ => "other",
+    }
+}
+
+// headers for the trace log, without the credentials
+fn redacted(headers: &[(String, String)]) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let secret =
+                name.eq_ignore_ascii_case("Authorization") || name.eq_ignore_ascii_case("Cookie");
+            (
+                name.as_str(),
+                if secret { "<redacted>" } else { value.as_str() },
+            )
+        })
+        .collect()
+}
+
+enum Handler<'a> {
+    Healthz(Arc<Host>),
+    Stats(Arc<Host>),
+    Metrics(Arc<Host>),
+    FleetMetrics,
+    /// the generator's own metrics, under metrics_generator_*
+    GeneratorMetrics,
+    Sd,
+    /// adding and removing a host at runtime
+    Host(&'a str),
+}
+
+fn route(request: &Request, state: &State, scope: &Scope) -> Result<Option<Response>> {
+    let fleet = &state.fleet;
+    let settings = state.settings();
+    let segments: Vec<&str> = request.path.split('/').skip(1).collect();
+
+    let handler = match scope {
+        Scope::Fleet => match segments.as_slice() {
+            ["healthz"] => fleet.first().map(Handler::Healthz),
+            ["stats"] => fleet.first().map(Handler::Stats),
+            ["metrics"] => Some(Handler::FleetMetrics),
+            ["metrics", "generator"] => Some(Handler::GeneratorMetrics),
+            ["sd"] => Some(Handler::Sd),
+            ["hosts", id] => Some(Handler::Host(id)),
+            ["hosts", id, "healthz"] => fleet.get(id).map(Handler::Healthz),
+            ["hosts", id, "stats"] => fleet.get(id).map(Handler::Stats),
+            ["hosts", id, "metrics"] => fleet.get(id).map(Handler::Metrics),
+            _ => None,
+        },
+        // a removed host keeps its listener, but has nothing left to serve
+        Scope::Host(id) => fleet.get(id).and_then(|host| match segments.as_slice() {
+            ["healthz"] => Some(Handler::Healthz(host)),
+            ["stats"] => Some(Handler::Stats(host)),
+            ["metrics"] => Some(Handler::Metrics(host)),
+            _ => None,
+        }),
+    };
+    let Some(handler) = handler else {
+        return Ok(Some(Response::new(404)));
+    };
+
+    // named like the [auth.endpoints] keys
+    let endpoint = match handler {
+        Handler::Healthz(_) => "healthz",
+        Handler::Stats(_) => "stats",
+        Handler::Metrics(_) | Handler::FleetMetrics | Handler::GeneratorMetrics => "metrics",
+        Handler::Sd => "sd",
+        Handler::Host(_) => "hosts",
+    };
+    // anyone who can reach the port could otherwise reshape the fleet
+    if endpoint == "hosts" && !settings.auth.protects(endpoint) {
+        return Ok(Some(Response::new(403).with_body(
+            "text/plain",
+            "adding and removing hosts needs credentials under [auth.endpoints] hosts\n",
+        )));
+    }
+    if let Err(response) = settings.auth.check(endpoint, request) {
+        debug!(endpoint, "unauthorized request");
+        return Ok(Some(response));
+    }
+
+    let allowed = match handler {
+        Handler::Host(_) => ["PUT", "DELETE"],
+        _ => ["GET", "HEAD"],
+    };
+    if !allowed.contains(&request.method.as_str()) {
+        return Ok(Some(
+            Response::new(405).with_header("Allow", &allowed.join(", ")),
+        ));
+    }
+
+    let format = metrics_format(request);
+    let host = match handler {
+        Handler::FleetMetrics => {
+            let buffer = state
+                .telemetry
+                .time_scrape(format, || fleet.scrape(format))?;
+            let response = metrics_response(format, buffer);
+            return compressed(request, &settings.config, response).map(Some);
+        }
+        Handler::GeneratorMetrics => {
+            let response = metrics_response(format, state.telemetry.scrape(format)?);
+            return compressed(request, &settings.config, response).map(Some);
+        }
+        Handler::Sd => return handle_sd(&state.fleet, &settings.config).map(Some),
+        Handler::Host(id) => {
+            return Ok(Some(handle_host(request, fleet, &settings.config, id)));
+        }
+        Handler::Metrics(host) => {
+            // a host scraped on its own answers nothing at all during an outage
+            let Some(snapshot) = host.snapshot() else {
+                return Ok(None);
+            };
+            let buffer = state.telemetry.time_scrape(format, || {
+                host.exporter
+                    .scrape([(host.id.as_str(), Some(snapshot))], format)
+            })?;
+            let response = metrics_response(format, buffer);
+            return compressed(request, &settings.config, response).map(Some);
+        }
+        Handler::Healthz(ref host) | Handler::Stats(ref host) => Arc::clone(host),
+    };
+
+    // the last tick's, the same one /metrics reports
+    let Some(snapshot) = host.snapshot() else {
+        return Ok(None);
+    };
+
+    Ok(match handler {
+        Handler::Stats(_) => {
+            let response = handle_stats(&snapshot, request.query("pretty").is_some())?;
+            Some(compressed(request, &settings.config, response)?)
+        }
+        _ => handle_healthz(&snapshot),
+    })
+}
+
+fn handle_stats(snapshot: &HostSnapshot, pretty: bool) -> Result<Response> {
+    let payload = &snapshot.metrics;
+    let payload_content = if pretty {
+        serde_json::to_string_pretty(payload)?
+    } else {
+        serde_json::to_string(payload)?
+    };
+    Ok(Response::new(200).with_body("application/json", payload_content))
+}
+
+fn handle_healthz(snapshot: &HostSnapshot) -> Option<Response> {
+    if snapshot.healthy {
+        Some(Response::new(200))
+    } else {
+        // an unhealthy server doesn't answer at all
+        None
+    }
+}
+
+// Picks the format the scraper likes best by the q-values of its Accept
+// header, the first one listed on a tie. Like the Go client, anything it
+// doesn't know, and no Accept at all, gets the Prometheus text format.
+fn metrics_format(request: &Request) -> Format {
+    let Some(accept) = request.header("Accept") else {
+        return Format::Text;
+    };
+
+    let mut best: Option<(Format, f64)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let mut quality = 1.0;
+        let mut params = Vec::new();
+        for param in parts {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().trim_matches('"');
+            if name == "q" {
+                quality = value.parse().unwrap_or(0.0);
+            } else {
+                params.push((name, value.to_string()));
+            }
+        }
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let format = match media_type.as_str() {
+            "application/vnd.google.protobuf"
+                if param("proto") == Some("io.prometheus.client.MetricFamily")
+                    && param("encoding").is_none_or(|encoding| encoding == "delimited") =>
+            {
+                Format::Protobuf
+            }
+            // 0.0.1 is the draft, what we write is a superset of it
+            "application/openmetrics-text"
+                if param("version")
+                    .is_none_or(|version| version == "1.0.0" || version == "0.0.1") =>
+            {
+                Format::OpenMetrics
+            }
+            "text/plain" if param("version").is_none_or(|version| version == "0.0.4") => {
+                Format::Text
+            }
+            "text/*" | "*/*" => Format::Text,
+            _ => continue,
+        };
+
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((format, quality));
+        }
+    }
+    best.map_or(Format::Text, |(format, _)| format)
+}
+
+fn metrics_response(format: Format, buffer: Vec<u8>) -> Response {
+    // caches must not hand one format to a scraper that asked for another
+    Response::new(200)
+        .with_vary("Accept")
+        .with_body(format.content_type(), buffer)
+}
+
+// compresses the body if the client takes gzip or zstd and it is worth it
+fn compressed(request: &Request, config: &Config, response: Response) -> Result<Response> {
+    let response = response.with_vary("Accept-Encoding");
+    if response.body.len() < config.compression_min_bytes {
+        return Ok(response);
+    }
+    let Some(encoding) = compress::negotiate(request.header("Accept-Encoding")) else {
+        return Ok(response);
+    };
+
+    let mut response = response.with_header("Content-Encoding", encoding.as_str());
+    // an io::Error here is no client gone away, it must still get its 500
+    response.body =
+        compress::compress(encoding, &response.body, config).map_err(Error::Compression)?;
+    Ok(response)
+}
+
+fn handle_sd(fleet: &Fleet, config: &Config) -> Result<Response> {
+    let groups = sd::http_sd(fleet, config.target_ip(), config.port)?;
+    Ok(Response::new(200).with_body("application/json", groups))
+}
+
+// PUT /hosts/<id> with the same fields as a [[host]] entry, all optional, as
+// the JSON body. DELETE /hosts/<id> takes it away again.
+fn handle_host(request: &Request, fleet: &Fleet, config: &Config, id: &str) -> Response {
+    if request.method == "DELETE" {
+        if !fleet.remove(id) {
+            return Response::new(404);
+        }
+        info!(host = id, "host removed");
+        update_file_sd(fleet, config);
+        return Response::new(204);
+    }
+
+    if fleet.get(id).is_some() {
+        return Response::new(409).with_body("text/plain", format!("host {id} exists already\n"));
+    }
+
+    // the details stay in the log, they may quote the generator's own files
+    let added = parse_host(&request.body, id)
+        .and_then(|host| config.host_spec(&host, None).map_err(|err| err.to_string()))
+        .and_then(|spec| fleet.add(spec).map_err(|err| err.to_string()));
+    if let Err(msg) = added {
+        warn!(host = id, "could not add host: {msg}");
+        return Response::new(400)
+            .with_body("text/plain", "invalid host, the generator's log says why\n");
+    }
+
+    info!(host = id, "host added");
+    Response::new(201)
+}
+
+fn parse_host(body: &[u8], id: &str) -> std::result::Result<HostConfig, String> {
+    let request = if body.is_empty() {
+        HostRequest::default()
+    } else {
+        serde_json::from_slice(body).map_err(|err| format!("invalid host: {err}"))?
+    };
+    Ok(request.host_config(id))
+}
+
+// hosts added at runtime have no listener of their own, but removed ones
+// may have had one
+fn update_file_sd(fleet: &Fleet, config: &Config) {
+    if let Some(path) = &config.file_sd_path {
+        if let Err(err) = sd::write_file_sd(path, fleet, config.target_ip()) {
+            warn!(path = %path.display(), "could not write file_sd targets: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Cursor;
+
+    // a connection whose client sends `input` and then hangs up
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn state() -> State {
+        let config = Config {
+            seed: Some(1),
+            ..Config::default()
+        };
+        let fleet = Fleet::new(&config).unwrap();
+        State::new(
+            fleet,
+            Arc::new(Telemetry::new()),
+            Settings::new(config).unwrap(),
+        )
+    }
+
+    // what the server writes back to a client sending `input`
+    fn exchange(state: &State, input: &[u8]) -> String {
+        let mut stream = Duplex {
+            input: Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        };
+        handle_stream(&mut stream, None, "test", state, &Scope::Fleet);
+        String::from_utf8_lossy(&stream.output).into_owned()
+    }
+
+    fn status_line(response: &str) -> &str {
+        response.lines().next().unwrap_or_default()
+    }
+
+    #[test]
+    fn answers_requests_on_one_connection() {
+        let response = exchange(
+            &state(),
+            b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\nGET /nope HTTP/1.1\r\nHost: x\r\n\r\n",
+        );
+        let statuses: Vec<&str> = response
+            .lines()
+            .filter(|line| line.starts_with("HTTP/1.1"))
+            .collect();
+        assert_eq!(statuses, ["HTTP/1.1 200 OK", "HTTP/1.1 404 Not Found"]);
+    }
+
+    #[test]
+    fn answers_malformed_requests_with_their_status() {
+        let long = format!("GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", "a".repeat(8 * 1024));
+        let many: String = (0..=100).map(|i| format!("X-{i}: a\r\n")).collect();
+        let many = format!("GET / HTTP/1.1\r\nHost: x\r\n{many}\r\n");
+        let cases: [(&[u8], &str); 8] = [
+            (b"GET /stats HTTP/1.1\r\n\r\n", "HTTP/1.1 400 Bad Request"),
+            (long.as_bytes(), "HTTP/1.1 414 URI Too Long"),
+            (
+                many.as_bytes(),
+                "HTTP/1.1 431 Request Header Fields Too Large",
+            ),
+            (
+                b"GET /\xff HTTP/1.1\r\nHost: x\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                b"GET / HTTP/2.0\r\nHost: x\r\n\r\n",
+                "HTTP/1.1 505 HTTP Version Not Supported",
+            ),
+            (
+                b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "HTTP/1.1 501 Not Implemented",
+            ),
+            (
+                b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: x\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                b"PUT /hosts/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n{",
+                "HTTP/1.1 400 Bad Request",
+            ),
+        ];
+        let state = state();
+        for (input, expected) in cases {
+            assert_eq!(status_line(&exchange(&state, input)), expected);
+        }
+    }
+
+    fn format_for(accept: &str) -> Format {
+        let request = Request {
+            method: "GET".into(),
+            path: "/metrics".into(),
+            query: Vec::new(),
+            version: Version::Http11,
+            headers: vec![("Accept".into(), accept.into())],
+            body: Vec::new(),
+        };
+        metrics_format(&request)
+    }
+
+    const PROTOBUF: &str =
+        "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily";
+
+    #[test]
+    fn picks_the_format_by_quality() {
+        let accept = format!("{PROTOBUF};encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3");
+        assert_eq!(format_for(&accept), Format::Protobuf);
+        assert_eq!(
+            format_for("text/plain;q=0.5,application/openmetrics-text;version=1.0.0;q=0.8"),
+            Format::OpenMetrics
+        );
+        // what Prometheus sends by default
+        assert_eq!(
+            format_for("application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"),
+            Format::OpenMetrics
+        );
+    }
+
+    #[test]
+    fn picks_the_first_on_a_tie() {
+        assert_eq!(
+            format_for("application/openmetrics-text,text/plain"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            format_for("text/plain;q=0.5,application/openmetrics-text;q=0.5"),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn never_picks_a_refused_format() {
+        assert_eq!(
+            format_for("application/openmetrics-text;q=0,text/plain;q=0.1"),
+            Format::Text
+        );
+        assert_eq!(format_for(&format!("{PROTOBUF};q=0")), Format::Text);
+    }
+
+    #[test]
+    fn falls_back_to_text() {
+        assert_eq!(format_for("*/*"), Format::Text);
+        assert_eq!(format_for("text/*"), Format::Text);
+        assert_eq!(format_for("application/json"), Format::Text);
+        assert_eq!(format_for(""), Format::Text);
+        assert_eq!(
+            format_for("application/openmetrics-text;version=2.0.0"),
+            Format::Text
+        );
+        assert_eq!(format_for("text/plain;version=1.0.0"), Format::Text);
+    }
+
+    #[test]
+    fn takes_protobuf_delimited_only() {
+        assert_eq!(format_for(PROTOBUF), Format::Protobuf);
+        assert_eq!(
+            format_for(&format!("{PROTOBUF};encoding=delimited")),
+            Format::Protobuf
+        );
+        assert_eq!(
+            format_for(&format!("{PROTOBUF};encoding=text")),
+            Format::Text
+        );
+        assert_eq!(
+            format_for("application/vnd.google.protobuf;encoding=delimited"),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn survives_byte_soup() {
+        let state = state();
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..500 {
+            let mut input = b"GET /stats HTTP/1.1\r\nHost: x\r\n\r\n".to_vec();
+            // flip, drop and insert bytes of a good request
+            for _ in 0..rng.gen_range(1..10) {
+                let at = rng.gen_range(0..input.len());
+                match rng.gen_range(0..3) {
+                    0 => input[at] = rng.gen(),
+                    1 => {
+                        input.remove(at);
+                    }
+                    _ => input.insert(at, rng.gen()),
+                }
+                if input.is_empty() {
+                    break;
+                }
+            }
+            let response = exchange(&state, &input);
+            assert!(response.is_empty() || response.starts_with("HTTP/1."));
+        }
+    }
+
+    #[test]
+    fn reload_keeps_the_self_signed_certificate() {
+        let config = Config {
+            tls_self_signed: true,
+            ..Config::default()
+        };
+        let running = Settings::new(config.clone()).unwrap();
+        let reloaded = Settings::reload(config.clone(), &running).unwrap();
+        assert!(Arc::ptr_eq(
+            running.tls.as_ref().unwrap(),
+            reloaded.tls.as_ref().unwrap()
+        ));
+
+        // turning it off is a change like any other
+        let plain = Config {
+            tls_self_signed: false,
+            ..config
+        };
+        assert!(Settings::reload(plain, &running).unwrap().tls.is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::config::{Cli, Config};
+use crate::logging;
+use crate::server::{Listener, Settings, State};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use tracing::{error, info, warn};
+
+/// Handles signals on a thread of its own. SIGTERM and SIGINT stop the
+/// listeners, so main can drain the connections and exit, and a second one
+/// exits right away. SIGHUP reloads the configuration.
+pub fn spawn(cli: Cli, state: Arc<State>, listeners: Vec<Listener>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => reload(&cli, &state),
+                _ if state.shutting_down() => {
+                    warn!("asked again, exiting without waiting for connections");
+                    std::process::exit(1);
+                }
+                _ => {
+                    info!("shutting down, no longer accepting connections");
+                    state.shut_down();
+                    for listener in &listeners {
+                        if let Err(err) = listener.stop() {
+                            warn!("failed to stop listener: {err}");
+                        }
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// reads the config file again, flags and environment variables are kept as
+// they were at startup. The simulated hosts keep running as they are.
+fn reload(cli: &Cli, state: &State) {
+    let running = state.settings();
+    let mut config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("reload failed, keeping the running configuration: {err}");
+            return;
+        }
+    };
+    for name in config.keep_restart_only(&running.config) {
+        warn!(
+            setting = name,
+            "only changes on a restart, keeping the running value"
+        );
+    }
+
+    match Settings::reload(config, &running) {
+        Ok(settings) => {
+            // log_level was checked by the load, so this has nothing to trip on
+            if let Err(err) = logging::set_level(&settings.config.log_level) {
+                warn!("{err}");
+            }
+            state.reload(settings);
+            info!("reloaded configuration");
+        }
+        Err(err) => error!("reload failed, keeping the running configuration: {err}"),
+    }
+}
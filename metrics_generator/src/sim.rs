@@ -0,0 +1,158 @@
+use crate::config::Profile;
+use crate::disk::DiskModel;
+use crate::load::{LoadModel, LOAD_FREQ};
+use crate::net::NetModel;
+use crate::scenario::{Health, Phase, Scenario};
+use crate::workload::Workload;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Where the simulation gets its notion of passing time from
+pub enum Clock {
+    /// follows the wall clock
+    Wall(Instant),
+    /// every tick moves time forward by the same step, which makes seeded
+    /// runs independent of how punctually the ticks come
+    Step(Duration),
+}
+
+impl Clock {
+    fn elapsed(&mut self) -> Duration {
+        match self {
+            Clock::Wall(last) => {
+                let now = Instant::now();
+                let elapsed = now - *last;
+                *last = now;
+                elapsed
+            }
+            Clock::Step(step) => *step,
+        }
+    }
+}
+
+/// State of the simulated server that carries over between requests
+pub struct Sim {
+    // the host being simulated, for the logs
+    host: String,
+    // a single generator for all the random values, so a seeded run with a
+    // fixed time step replays exactly tick for tick
+    pub rng: StdRng,
+    pub load: LoadModel,
+    pub disks: DiskModel,
+    pub net: NetModel,
+    pub workload: Workload,
+    restarts_per_day: f64,
+    clock: Clock,
+    scenario: Option<Scenario>,
+    // simulated time since start, and the scenario phase it is in
+    elapsed: Duration,
+    // time passed that hasn't added up to a full tick of the models yet
+    pending: Duration,
+    phase_index: Option<usize>,
+}
+
+impl Sim {
+    pub fn new(
+        host: &str,
+        seed: u64,
+        profile: &Profile,
+        time_step_ms: Option<u64>,
+        latency_buckets: &[f64],
+        native_histogram_schema: i32,
+        scenario: Option<Scenario>,
+    ) -> Sim {
+        let clock = match time_step_ms {
+            Some(step) => Clock::Step(Duration::from_millis(step)),
+            None => Clock::Wall(Instant::now()),
+        };
+
+        Sim {
+            host: host.to_string(),
+            rng: StdRng::seed_from_u64(seed),
+            load: LoadModel::new(profile.core_count),
+            disks: DiskModel::new(&profile.disks),
+            net: NetModel::new(&profile.interfaces),
+            workload: Workload::new(&profile.endpoints, latency_buckets, native_histogram_schema),
+            restarts_per_day: profile.restarts_per_day,
+            clock,
+            scenario,
+            elapsed: Duration::ZERO,
+            pending: Duration::ZERO,
+            phase_index: None,
+        }
+    }
+
+    /// Catches the simulation up with the time passed since the last tick
+    pub fn advance(&mut self) {
+        let elapsed = self.clock.elapsed();
+        let was_down = self.outage();
+
+        // every model moves in whole ticks of LOAD_FREQ, the load share being
+        // how busy the CPUs are, 1.0 being all cores
+        self.pending += elapsed;
+        let (pending, tick) = (self.pending.as_nanos(), LOAD_FREQ.as_nanos());
+        let ticks = (pending / tick) as u64;
+        self.pending = Duration::from_nanos((pending % tick) as u64);
+
+        let cpu = self.phase().and_then(|(phase, _)| phase.cpu);
+        self.load.force_share(cpu);
+        self.load.advance(&mut self.rng, ticks);
+        self.disks.advance(&mut self.rng, ticks, self.load.share());
+        self.net.advance(&mut self.rng, ticks, self.load.share());
+        self.workload
+            .advance(&mut self.rng, ticks, self.load.share());
+
+        self.elapsed += elapsed;
+        self.log_phase_change();
+
+        // a box coming back from an outage has been rebooted, and any box
+        // reboots now and then
+        let per_sec = self.restarts_per_day / 86400.0;
+        let restart_chance = 1.0 - (-per_sec * elapsed.as_secs_f64()).exp();
+        if (was_down && !self.outage()) || self.rng.gen_bool(restart_chance) {
+            self.restart();
+        }
+    }
+
+    // counters start over, everything else carries on as it was
+    fn restart(&mut self) {
+        info!(host = %self.host, "simulated restart");
+        self.disks.reset();
+        self.net.reset();
+        self.workload.reset();
+    }
+
+    /// The scenario phase in effect and how far into it the simulation is
+    pub fn phase(&self) -> Option<(&Phase, Duration)> {
+        let (_, phase, into) = self.scenario.as_ref()?.phase_at(self.elapsed)?;
+        Some((phase, into))
+    }
+
+    /// True while a scenario has the whole server down
+    pub fn outage(&self) -> bool {
+        self.phase()
+            .is_some_and(|(phase, _)| phase.health == Health::Outage)
+    }
+
+    fn log_phase_change(&mut self) {
+        let Some(scenario) = &self.scenario else {
+            return;
+        };
+
+        let current = scenario.phase_at(self.elapsed);
+        let index = current.map(|(index, _, _)| index);
+        if index == self.phase_index {
+            return;
+        }
+        self.phase_index = index;
+
+        match current {
+            Some((_, phase, _)) => {
+                info!(host = %self.host, phase = %phase.name, "scenario phase started")
+            }
+            None => info!(host = %self.host, "scenario finished"),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::family::SortedFamily;
+use crate::histogram::{HistogramTotals, MirroredHistogram, MirroredSummary, SummaryTotals};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use std::sync::atomic::AtomicU64;
+
+/// A metric family read back out of the exporter, for the encoders
+/// prometheus_client doesn't come with
+pub struct Family {
+    /// as registered, so without the _total of counters
+    pub name: String,
+    pub help: String,
+    pub kind: Kind,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+}
+
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: Value,
+}
+
+pub enum Value {
+    Counter(f64),
+    Gauge(f64),
+    Histogram(HistogramTotals),
+    Summary(SummaryTotals),
+}
+
+/// A label set as name and value pairs, in the order of the fields
+pub trait LabelPairs {
+    fn pairs(&self) -> Vec<(&'static str, String)>;
+}
+
+// for the families that only ever have the one member
+impl LabelPairs for () {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+}
+
+/// A metric whose current value can be read back
+pub trait ReadValue {
+    const KIND: Kind;
+    fn read(&self) -> Value;
+}
+
+impl ReadValue for Gauge {
+    const KIND: Kind = Kind::Gauge;
+    fn read(&self) -> Value {
+        Value::Gauge(self.get() as f64)
+    }
+}
+
+impl ReadValue for Gauge<f64, AtomicU64> {
+    const KIND: Kind = Kind::Gauge;
+    fn read(&self) -> Value {
+        Value::Gauge(self.get())
+    }
+}
+
+impl ReadValue for Counter {
+    const KIND: Kind = Kind::Counter;
+    fn read(&self) -> Value {
+        Value::Counter(self.get() as f64)
+    }
+}
+
+impl ReadValue for Counter<f64, AtomicU64> {
+    const KIND: Kind = Kind::Counter;
+    fn read(&self) -> Value {
+        Value::Counter(self.get())
+    }
+}
+
+impl ReadValue for MirroredHistogram {
+    const KIND: Kind = Kind::Histogram;
+    fn read(&self) -> Value {
+        Value::Histogram(self.get())
+    }
+}
+
+impl ReadValue for MirroredSummary {
+    const KIND: Kind = Kind::Summary;
+    fn read(&self) -> Value {
+        Value::Summary(self.get())
+    }
+}
+
+/// A family of metrics that can be read back as a whole
+pub trait Snapshot: Send + Sync {
+    fn kind(&self) -> Kind;
+    fn samples(&self) -> Vec<Sample>;
+}
+
+impl<S, M> Snapshot for SortedFamily<S, M>
+where
+    S: LabelPairs + Clone + Ord + Send + Sync,
+    M: ReadValue + Clone + Default + Send + Sync,
+{
+    fn kind(&self) -> Kind {
+        M::KIND
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        self.members()
+            .into_iter()
+            .map(|(labels, metric)| Sample {
+                labels: labels.pairs(),
+                value: metric.read(),
+            })
+            .collect()
+    }
+}
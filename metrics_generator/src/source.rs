@@ -0,0 +1,361 @@
+use crate::config::{ConfigError, Profile};
+use crate::generator::{
+    gen_health_status, gen_metrics_cpu, gen_metrics_disk, gen_metrics_latency, gen_metrics_mem,
+    gen_metrics_net, MetricsRoot,
+};
+use crate::procfs;
+use crate::sim::Sim;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::warn;
+
+/// What a host reports at one point in time, the /stats body along with
+/// whether its health check passes
+#[derive(Clone, Deserialize)]
+pub struct HostSnapshot {
+    #[serde(flatten)]
+    pub metrics: MetricsRoot,
+    #[serde(default = "healthy")]
+    pub healthy: bool,
+}
+
+fn healthy() -> bool {
+    true
+}
+
+// what float64 spans at the finest native histogram schema
+const MAX_NATIVE_INDEX: i32 = 1024 << 8;
+
+impl HostSnapshot {
+    /// Checks what the simulation makes sure of by itself, for snapshots
+    /// coming from elsewhere: label values the config would take, and
+    /// histograms whose buckets add up
+    fn validate(&self) -> Result<(), String> {
+        let metrics = &self.metrics;
+        if metrics.memory.used_bytes > metrics.memory.total_bytes {
+            return Err("memory used_bytes is above total_bytes".into());
+        }
+
+        let mut mounts = HashSet::new();
+        for disk in &metrics.disk {
+            if !disk.mount.starts_with('/') || !is_plain(&disk.mount) {
+                return Err(format!(
+                    "disk mount {:?} is no plain absolute path",
+                    disk.mount
+                ));
+            }
+            if !mounts.insert(&disk.mount) {
+                return Err(format!("duplicate disk mount {:?}", disk.mount));
+            }
+        }
+
+        let mut devices = HashSet::new();
+        for net in &metrics.network {
+            if net.device.is_empty() || !is_plain(&net.device) {
+                return Err(format!("network device {:?} is no plain name", net.device));
+            }
+            if !devices.insert(&net.device) {
+                return Err(format!("duplicate network device {:?}", net.device));
+            }
+        }
+
+        let mut endpoints = HashSet::new();
+        for latency in &metrics.latency {
+            let endpoint = &latency.endpoint;
+            if !endpoint.starts_with('/') || !is_plain(endpoint) {
+                return Err(format!("endpoint {endpoint:?} is no plain path"));
+            }
+            if !endpoints.insert(endpoint) {
+                return Err(format!("duplicate endpoint {endpoint:?}"));
+            }
+            if !(latency.sum_seconds >= 0.0 && latency.sum_seconds.is_finite()) {
+                return Err(format!(
+                    "endpoint {endpoint:?}: sum_seconds must be 0 or more"
+                ));
+            }
+
+            // cumulative, so neither bounds nor counts may go down
+            let mut below = (0.0, 0);
+            for bucket in &latency.buckets {
+                if !(bucket.le > below.0 && bucket.le.is_finite()) || bucket.count < below.1 {
+                    return Err(format!(
+                        "endpoint {endpoint:?}: buckets must go up in le and count"
+                    ));
+                }
+                below = (bucket.le, bucket.count);
+            }
+            if below.1 > latency.count {
+                return Err(format!(
+                    "endpoint {endpoint:?}: a bucket counts more than count"
+                ));
+            }
+
+            if latency
+                .quantiles
+                .iter()
+                .any(|q| !(0.0..=1.0).contains(&q.quantile))
+            {
+                return Err(format!(
+                    "endpoint {endpoint:?}: quantiles must be between 0 and 1"
+                ));
+            }
+
+            let native = &latency.native;
+            if !(-4..=8).contains(&native.schema) {
+                return Err(format!(
+                    "endpoint {endpoint:?}: native schema must be between -4 and 8"
+                ));
+            }
+            if native
+                .buckets
+                .keys()
+                .any(|index| index.abs() > MAX_NATIVE_INDEX)
+            {
+                return Err(format!(
+                    "endpoint {endpoint:?}: native bucket indices must be between \
+                     -{MAX_NATIVE_INDEX} and {MAX_NATIVE_INDEX}"
+                ));
+            }
+            let native_count = native
+                .buckets
+                .values()
+                .try_fold(0u64, |total, &count| total.checked_add(count));
+            if native_count.is_none_or(|total| total > latency.count) {
+                return Err(format!(
+                    "endpoint {endpoint:?}: native buckets count more than count"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// fine as a label value as it is, like the config wants endpoint paths
+fn is_plain(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\')
+}
+
+/// Provides the values of a host. A snapshot is taken on every tick and
+/// served until the next, so a source decides for itself how time moves on
+/// between them.
+pub trait MetricSource: Send {
+    /// The values as of now, None while the host is down and answers nothing
+    fn snapshot(&mut self) -> Option<HostSnapshot>;
+}
+
+/// Everything made up by the simulation, following the scenario if any
+pub struct RandomSource {
+    sim: Sim,
+    profile: Profile,
+}
+
+impl RandomSource {
+    pub fn new(sim: Sim, profile: Profile) -> RandomSource {
+        RandomSource { sim, profile }
+    }
+}
+
+impl MetricSource for RandomSource {
+    fn snapshot(&mut self) -> Option<HostSnapshot> {
+        let sim = &mut self.sim;
+        let profile = &self.profile;
+        sim.advance();
+        if sim.outage() {
+            return None;
+        }
+
+        Some(HostSnapshot {
+            healthy: gen_health_status(sim, profile),
+            metrics: MetricsRoot {
+                cpu: gen_metrics_cpu(&sim.load, profile.core_count),
+                memory: gen_metrics_mem(sim, profile.total_bytes),
+                disk: gen_metrics_disk(&sim.disks),
+                network: gen_metrics_net(&sim.net),
+                latency: gen_metrics_latency(&sim.workload),
+            },
+        })
+    }
+}
+
+/// The CPU load and memory of the machine the generator runs on, the rest
+/// simulated as usual
+pub struct HostSource {
+    simulated: RandomSource,
+}
+
+impl HostSource {
+    pub fn new(simulated: RandomSource) -> HostSource {
+        HostSource { simulated }
+    }
+}
+
+impl MetricSource for HostSource {
+    fn snapshot(&mut self) -> Option<HostSnapshot> {
+        let mut snapshot = self.simulated.snapshot()?;
+        // checked at startup, so a failure here is the odd one out
+        match procfs::cpu() {
+            Ok(cpu) => snapshot.metrics.cpu = cpu,
+            Err(err) => warn!("could not read the CPU load from /proc, simulating it: {err}"),
+        }
+        match procfs::memory() {
+            Ok(memory) => snapshot.metrics.memory = memory,
+            Err(err) => warn!("could not read /proc/meminfo, simulating memory: {err}"),
+        }
+        Some(snapshot)
+    }
+}
+
+/// Plays back recorded snapshots one after the other, starting over after
+/// the last one
+pub struct ReplaySource {
+    recorded: Arc<[Recorded]>,
+    next: usize,
+}
+
+/// A line of a replay file: a /stats body, optionally with `healthy` and
+/// `down` added
+#[derive(Clone, Deserialize)]
+pub struct Recorded {
+    #[serde(flatten)]
+    snapshot: HostSnapshot,
+    /// the host answers nothing at all, like during an outage
+    #[serde(default)]
+    down: bool,
+}
+
+impl ReplaySource {
+    pub fn new(recorded: Arc<[Recorded]>) -> ReplaySource {
+        ReplaySource { recorded, next: 0 }
+    }
+}
+
+impl MetricSource for ReplaySource {
+    fn snapshot(&mut self) -> Option<HostSnapshot> {
+        let recorded = &self.recorded[self.next];
+        self.next = (self.next + 1) % self.recorded.len();
+        (!recorded.down).then(|| recorded.snapshot.clone())
+    }
+}
+
+/// Reads a replay file, JSON lines of /stats bodies as captured with curl.
+/// Blank lines are left out.
+pub fn read_replay(path: &Path) -> Result<Arc<[Recorded]>, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+    let recorded: Vec<Recorded> = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let invalid = |err: String| {
+                ConfigError::Invalid(format!("{} line {}: {err}", path.display(), index + 1))
+            };
+            let recorded: Recorded =
+                serde_json::from_str(line).map_err(|err| invalid(err.to_string()))?;
+            recorded.snapshot.validate().map_err(invalid)?;
+            Ok(recorded)
+        })
+        .collect::<Result<_, _>>()?;
+
+    if recorded.is_empty() {
+        return Err(ConfigError::Invalid(format!(
+            "{} has nothing to replay",
+            path.display()
+        )));
+    }
+    Ok(recorded.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // a line of /stats as the simulation writes it, with `edit` applied
+    fn replay_line(edit: impl FnOnce(&mut serde_json::Value)) -> String {
+        let mut line = serde_json::json!({
+            "cpu": {"load_1m": 1.0, "load_5m": 1.0, "load_15m": 1.0, "thread_count": 4},
+            "memory": {"used_bytes": 1, "total_bytes": 2},
+            "disk": [],
+            "network": [],
+            "latency": [{
+                "endpoint": "/login",
+                "count": 3,
+                "sum_seconds": 0.3,
+                "buckets": [{"le": 0.1, "count": 1}, {"le": 0.5, "count": 3}],
+                "quantiles": [{"quantile": 0.5, "seconds": 0.1}],
+                "native": {"schema": 3, "buckets": {"-27": 1, "-8": 2}},
+            }],
+        });
+        edit(&mut line);
+        line.to_string()
+    }
+
+    // a name for the temporary file and what to change about the good line
+    type Edit = (&'static str, fn(&mut serde_json::Value));
+
+    fn read(name: &str, line: &str) -> Result<Arc<[Recorded]>, ConfigError> {
+        let path = env::temp_dir().join(format!("metrics_generator_{name}.jsonl"));
+        fs::write(&path, line).unwrap();
+        let read = read_replay(&path);
+        let _ = fs::remove_file(&path);
+        read
+    }
+
+    #[test]
+    fn reads_a_recorded_snapshot() {
+        assert_eq!(read("good", &replay_line(|_| {})).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refuses_broken_histograms() {
+        let edits: [Edit; 4] = [
+            ("over_count", |line| {
+                line["latency"][0]["buckets"][1]["count"] = 4.into()
+            }),
+            ("going_down", |line| {
+                line["latency"][0]["buckets"][1]["count"] = 0.into()
+            }),
+            ("native_over_count", |line| {
+                line["latency"][0]["native"]["buckets"]["-8"] = 5.into()
+            }),
+            ("native_index", |line| {
+                line["latency"][0]["native"]["buckets"] = serde_json::json!({"2147483647": 1})
+            }),
+        ];
+        for (name, edit) in edits {
+            assert!(read(name, &replay_line(edit)).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_labels_the_config_would() {
+        let edits: [Edit; 3] = [
+            ("quoted_endpoint", |line| {
+                line["latency"][0]["endpoint"] = "/a\"b".into()
+            }),
+            ("relative_mount", |line| {
+                line["disk"] = serde_json::json!([{
+                    "mount": "data", "used_bytes": 0, "total_bytes": 1, "used_inodes": 0,
+                    "total_inodes": 1, "read_bytes": 0, "written_bytes": 0, "reads": 0,
+                    "writes": 0, "latency_seconds": 0.0,
+                }])
+            }),
+            ("empty_device", |line| {
+                line["network"] = serde_json::json!([{
+                    "device": "", "rx_bytes": 0, "tx_bytes": 0, "rx_packets": 0,
+                    "tx_packets": 0, "rx_errors": 0, "tx_errors": 0, "rx_dropped": 0,
+                    "tx_dropped": 0,
+                }])
+            }),
+        ];
+        for (name, edit) in edits {
+            assert!(read(name, &replay_line(edit)).is_err(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use crate::error::Result;
+use crate::exporter::{encode_families, register, Described, Format};
+use crate::family::SortedFamily;
+use crate::histogram::MirroredHistogram;
+use crate::snapshot::LabelPairs;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Prefix of the generator's own metrics, apart from the simulated ones
+pub const NAMESPACE: &str = "metrics_generator";
+
+// the Prometheus client defaults, a request or scrape should land well inside
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// the unit of the CPU times in /proc/self/stat, 100 on every Linux the Go
+// client's procfs library knows of, which assumes it just the same
+const USER_HZ: f64 = 100.0;
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct RequestLabels {
+    path: String,
+    status: String,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct PathLabels {
+    path: String,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Ord, PartialOrd, EncodeLabelSet, Debug)]
+pub struct FormatLabels {
+    format: String,
+}
+
+impl LabelPairs for RequestLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("path", self.path.clone()), ("status", self.status.clone())]
+    }
+}
+
+impl LabelPairs for PathLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("path", self.path.clone())]
+    }
+}
+
+impl LabelPairs for FormatLabels {
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![("format", self.format.clone())]
+    }
+}
+
+/// What the generator itself is up to, so a slow or failing scrape can be
+/// told apart from a simulated server having a bad day. Served apart from the
+/// simulated metrics, which a seed keeps the same from run to run.
+pub struct Telemetry {
+    registry: Registry,
+    described: Vec<Described>,
+    requests: SortedFamily<RequestLabels, Counter>,
+    request_duration: SortedFamily<PathLabels, MirroredHistogram>,
+    open_connections: SortedFamily<(), Gauge>,
+    scrapes: SortedFamily<FormatLabels, Counter>,
+    scrape_duration: SortedFamily<FormatLabels, MirroredHistogram>,
+    cpu_seconds: SortedFamily<(), Counter<f64, AtomicU64>>,
+    resident_memory: SortedFamily<(), Gauge>,
+    virtual_memory: SortedFamily<(), Gauge>,
+    open_fds: SortedFamily<(), Gauge>,
+    max_fds: SortedFamily<(), Gauge>,
+    start_time: SortedFamily<(), Gauge<f64, AtomicU64>>,
+}
+
+/// Counts as an open connection until dropped
+pub struct OpenConnection(Gauge);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        let mut telemetry = Telemetry {
+            registry: Registry::default(),
+            described: Vec::new(),
+            requests: SortedFamily::default(),
+            request_duration: SortedFamily::default(),
+            open_connections: SortedFamily::default(),
+            scrapes: SortedFamily::default(),
+            scrape_duration: SortedFamily::default(),
+            cpu_seconds: SortedFamily::default(),
+            resident_memory: SortedFamily::default(),
+            virtual_memory: SortedFamily::default(),
+            open_fds: SortedFamily::default(),
+            max_fds: SortedFamily::default(),
+            start_time: SortedFamily::default(),
+        };
+
+        // close enough to when the process started, and no parsing of boot times
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        telemetry
+            .start_time
+            .get_or_create(&())
+            .set(started.as_secs_f64());
+        telemetry.open_connections.get_or_create(&());
+
+        let mut registry = Registry::default();
+        let mut described = Vec::new();
+        telemetry.register(&mut registry, &mut described);
+        telemetry.registry = registry;
+        telemetry.described = described;
+        telemetry
+    }
+
+    /// Renders the generator's own metrics, with fresh process stats
+    pub fn scrape(&self, format: Format) -> Result<Vec<u8>> {
+        self.refresh_process();
+        encode_families(&self.registry, &self.described, format)
+    }
+
+    fn register(&self, registry: &mut Registry, described: &mut Vec<Described>) {
+        // counters get their _total suffix from the encoder
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_http_requests"),
+            "HTTP requests answered by the generator, by path and status code",
+            &self.requests,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_http_request_duration_seconds"),
+            "time from reading a request to having written the response",
+            &self.request_duration,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_open_connections"),
+            "connections being served right now",
+            &self.open_connections,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_scrapes"),
+            "scrapes of /metrics, by exposition format",
+            &self.scrapes,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_scrape_duration_seconds"),
+            "time spent sampling the hosts and encoding a /metrics response",
+            &self.scrape_duration,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_cpu_seconds"),
+            "user and system CPU time of the generator",
+            &self.cpu_seconds,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_resident_memory_bytes"),
+            "resident memory size of the generator in bytes",
+            &self.resident_memory,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_virtual_memory_bytes"),
+            "virtual memory size of the generator in bytes",
+            &self.virtual_memory,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_open_fds"),
+            "file descriptors the generator has open",
+            &self.open_fds,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_max_fds"),
+            "limit on the file descriptors the generator may open",
+            &self.max_fds,
+        );
+
+        register(
+            registry,
+            described,
+            format!("{NAMESPACE}_process_start_time_seconds"),
+            "start time of the generator since the unix epoch in seconds",
+            &self.start_time,
+        );
+    }
+
+    /// Counts a request, `path` being the route with the host id left out so
+    /// the label values stay few. No status is a connection dropped unanswered.
+    pub fn observe_request(&self, path: &str, status: Option<u16>, duration: Duration) {
+        let status = status.map_or_else(|| "none".to_string(), |status| status.to_string());
+        self.requests
+            .get_or_create(&RequestLabels {
+                path: path.to_string(),
+                status,
+            })
+            .inc();
+        self.request_duration
+            .get_or_create(&PathLabels {
+                path: path.to_string(),
+            })
+            .observe(&DURATION_BUCKETS, duration.as_secs_f64());
+    }
+
+    pub fn open_connection(&self) -> OpenConnection {
+        let gauge = self.open_connections.get_or_create(&());
+        gauge.inc();
+        OpenConnection(gauge)
+    }
+
+    /// Runs a scrape of the simulated metrics, counting it and how long it took
+    pub fn time_scrape<T>(&self, format: Format, scrape: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let scraped = scrape();
+        let labels = FormatLabels {
+            format: format.as_str().to_string(),
+        };
+        self.scrapes.get_or_create(&labels).inc();
+        self.scrape_duration
+            .get_or_create(&labels)
+            .observe(&DURATION_BUCKETS, started.elapsed().as_secs_f64());
+        scraped
+    }
+
+    // reads the process stats from /proc/self, leaving the ones it can't read
+    // as they were
+    fn refresh_process(&self) {
+        let stat = fs::read_to_string("/proc/self/stat");
+        if let Some(seconds) = stat.ok().as_deref().and_then(cpu_seconds) {
+            self.cpu_seconds
+                .get_or_create(&())
+                .inner()
+                .store(seconds.to_bits(), Ordering::Relaxed);
+        }
+
+        if let Ok(status) = fs::read_to_string("/proc/self/status") {
+            if let Some(bytes) = status_bytes(&status, "VmRSS") {
+                self.resident_memory.get_or_create(&()).set(bytes);
+            }
+            if let Some(bytes) = status_bytes(&status, "VmSize") {
+                self.virtual_memory.get_or_create(&()).set(bytes);
+            }
+        }
+
+        // the directory being read counts as one of them, as it does for the Go client
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            self.open_fds.get_or_create(&()).set(fds.count() as i64);
+        }
+
+        let limits = fs::read_to_string("/proc/self/limits");
+        if let Some(max) = limits.ok().as_deref().and_then(max_fds) {
+            self.max_fds.get_or_create(&()).set(max);
+        }
+    }
+}
+
+// utime and stime, fields 14 and 15 of /proc/self/stat
+fn cpu_seconds(stat: &str) -> Option<f64> {
+    // the command name in parentheses may hold spaces, so count from after
+    // it, where the state is field 3
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let ticks = |field: usize| fields.get(field - 3)?.parse::<u64>().ok();
+    Some((ticks(14)? + ticks(15)?) as f64 / USER_HZ)
+}
+
+// a line such as "VmRSS:     5120 kB"
+fn status_bytes(status: &str, key: &str) -> Option<i64> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?;
+    let kb: i64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+// the soft limit, from "Max open files  1024  524288  files"
+fn max_fds(limits: &str) -> Option<i64> {
+    let line = limits
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))?;
+    line.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cpu_time_past_the_command_name() {
+        let stat = "42 (metrics_generator) S 1 42 42 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 9 0";
+        assert_eq!(cpu_seconds(stat), Some(3.0));
+
+        // a name with spaces and parentheses of its own
+        let stat = "42 (gen (1) x) S 1 42 42 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 9 0";
+        assert_eq!(cpu_seconds(stat), Some(3.0));
+        let stat = "42 (a) b) S 1 42 42 0 -1 4194560 100 0 0 0 7 3 0 0 20 0 9 0";
+        assert_eq!(cpu_seconds(stat), Some(0.1));
+
+        assert_eq!(cpu_seconds("42 (cut short) S 1 42"), None);
+        assert_eq!(cpu_seconds(""), None);
+    }
+
+    #[test]
+    fn reads_memory_from_status() {
+        let status = "Name:\tmetrics_generator\nVmPeak:\t   20480 kB\nVmSize:\t   10240 kB\nVmRSS:\t    5120 kB\n";
+        assert_eq!(status_bytes(status, "VmRSS"), Some(5120 * 1024));
+        assert_eq!(status_bytes(status, "VmSize"), Some(10240 * 1024));
+        // a kernel thread has no memory of its own to report
+        assert_eq!(status_bytes("Name:\tkthreadd\n", "VmRSS"), None);
+        assert_eq!(status_bytes("VmRSS:\tlots\n", "VmRSS"), None);
+    }
+
+    #[test]
+    fn reads_the_soft_limit_of_open_files() {
+        let limits = "\
+Limit                     Soft Limit           Hard Limit           Units
+Max processes             63459                63459                processes
+Max open files            1024                 524288               files
+Max locked memory         8388608              8388608              bytes
+";
+        assert_eq!(max_fds(limits), Some(1024));
+        let unlimited = limits.replace("1024 ", "unlimited");
+        assert_eq!(max_fds(&unlimited), None);
+        assert_eq!(max_fds(""), None);
+    }
+}
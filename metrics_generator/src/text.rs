@@ -0,0 +1,102 @@
+use crate::snapshot::{Family, Kind, Value};
+use std::fmt::Write;
+
+/// The classic Prometheus text format, for scrapers that predate OpenMetrics
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders the families in the Prometheus text format 0.0.4. Unlike
+/// OpenMetrics it has no # EOF and no _created series, and counters carry
+/// their _total in the family name as well.
+pub fn encode(families: &[Family]) -> Result<String, std::fmt::Error> {
+    let mut out = String::new();
+    for family in families {
+        let (name, kind) = match family.kind {
+            Kind::Counter => (format!("{}_total", family.name), "counter"),
+            Kind::Gauge => (family.name.clone(), "gauge"),
+            Kind::Histogram => (family.name.clone(), "histogram"),
+            Kind::Summary => (family.name.clone(), "summary"),
+        };
+        writeln!(out, "# HELP {name} {}", escape_help(&family.help))?;
+        writeln!(out, "# TYPE {name} {kind}")?;
+
+        for sample in &family.samples {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                .collect();
+            // the labels of a sample, with one more for buckets and quantiles
+            let with = |extra: Option<(&str, f64)>| {
+                let mut labels = labels.clone();
+                if let Some((label, value)) = extra {
+                    labels.push(format!("{label}=\"{}\"", format_value(value)));
+                }
+                if labels.is_empty() {
+                    String::new()
+                } else {
+                    format!("{{{}}}", labels.join(","))
+                }
+            };
+
+            match &sample.value {
+                Value::Counter(value) | Value::Gauge(value) => {
+                    writeln!(out, "{name}{} {}", with(None), format_value(*value))?
+                }
+                Value::Histogram(totals) => {
+                    let mut cumulative = 0;
+                    for &(bound, count) in &totals.buckets {
+                        cumulative += count;
+                        // the +Inf bucket is kept as f64::MAX
+                        let bound = if bound == f64::MAX {
+                            f64::INFINITY
+                        } else {
+                            bound
+                        };
+                        writeln!(
+                            out,
+                            "{name}_bucket{} {cumulative}",
+                            with(Some(("le", bound)))
+                        )?;
+                    }
+                    writeln!(out, "{name}_sum{} {}", with(None), format_value(totals.sum))?;
+                    writeln!(out, "{name}_count{} {}", with(None), totals.count)?;
+                }
+                Value::Summary(totals) => {
+                    for &(quantile, value) in &totals.quantiles {
+                        writeln!(
+                            out,
+                            "{name}{} {}",
+                            with(Some(("quantile", quantile))),
+                            format_value(value)
+                        )?;
+                    }
+                    writeln!(out, "{name}_sum{} {}", with(None), format_value(totals.sum))?;
+                    writeln!(out, "{name}_count{} {}", with(None), totals.count)?;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+// the way the Go client writes them, Rust would say inf and -inf
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
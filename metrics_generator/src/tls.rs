@@ -0,0 +1,210 @@
+use crate::config::{Config, ConfigError};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+// names the generated certificate is good for, what a local scrape dials
+const SELF_SIGNED_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// The TLS settings the listeners share, None when serving plain HTTP
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, ConfigError> {
+    if !config.tls_enabled() {
+        return Ok(None);
+    }
+
+    let (certs, key) = if config.tls_self_signed {
+        self_signed(config)?
+    } else {
+        // validate() makes sure both are there
+        let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path)
+        else {
+            return Ok(None);
+        };
+        load(cert_path, key_path)?
+    };
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| ConfigError::Tls(err.to_string()))?;
+
+    let builder = match &config.tls_client_ca_path {
+        Some(path) => {
+            let verifier = client_verifier(path, provider)?;
+            info!(ca = %path.display(), "requiring client certificates");
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| ConfigError::Tls(format!("unusable certificate or key: {err}")))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(server_config)))
+}
+
+fn load(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ConfigError> {
+    let certs = read_certs(cert_path)?;
+    let pem = fs::read(key_path).map_err(|err| ConfigError::Read(key_path.into(), err))?;
+    let key = PrivateKeyDer::from_pem_slice(&pem).map_err(|err| {
+        ConfigError::Tls(format!("no private key in {}: {err}", key_path.display()))
+    })?;
+    Ok((certs, key))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let pem = fs::read(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ConfigError::Tls(format!("bad certificate in {}: {err}", path.display())))?;
+    if certs.is_empty() {
+        return Err(ConfigError::Tls(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+// a fresh certificate for every start, written out when there are paths for
+// it so scrapers can be pointed at it as their CA. Files already there are
+// left alone, they may well be a real certificate and key
+fn self_signed(
+    config: &Config,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ConfigError> {
+    let names = SELF_SIGNED_NAMES.map(String::from).to_vec();
+    let generated = rcgen::generate_simple_self_signed(names)
+        .map_err(|err| ConfigError::Tls(format!("could not generate a certificate: {err}")))?;
+
+    if let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) {
+        for path in [cert_path, key_path] {
+            if path.exists() {
+                return Err(ConfigError::Tls(format!(
+                    "{} already exists, remove it or pick another path for the self-signed certificate",
+                    path.display()
+                )));
+            }
+        }
+        write_pem(cert_path, &generated.cert.pem(), 0o644)?;
+        // nobody but us has any business reading the key
+        write_pem(key_path, &generated.signing_key.serialize_pem(), 0o600)?;
+        info!(path = %cert_path.display(), "wrote self-signed certificate");
+    } else {
+        info!("serving a self-signed certificate");
+    }
+
+    let cert = generated.cert.der().clone();
+    let key = PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der());
+    Ok((vec![cert], key.into()))
+}
+
+fn write_pem(path: &Path, pem: &str, mode: u32) -> Result<(), ConfigError> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|err| ConfigError::Tls(format!("could not write {}: {err}", path.display())))
+}
+
+fn client_verifier(
+    path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, ConfigError> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|err| ConfigError::Tls(format!("bad CA in {}: {err}", path.display())))?;
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|err| ConfigError::Tls(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn paths(name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir();
+        let cert = dir.join(format!("metrics_generator_{name}.crt"));
+        let key = dir.join(format!("metrics_generator_{name}.key"));
+        let _ = fs::remove_file(&cert);
+        let _ = fs::remove_file(&key);
+        (cert, key)
+    }
+
+    fn self_signed_config(cert: &Path, key: &Path) -> Config {
+        Config {
+            tls_self_signed: true,
+            tls_cert_path: Some(cert.into()),
+            tls_key_path: Some(key.into()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn writes_the_self_signed_pair_once() {
+        let (cert, key) = paths("self_signed");
+        let config = self_signed_config(&cert, &key);
+        assert!(server_config(&config).unwrap().is_some());
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&cert), 0o644);
+        assert_eq!(mode(&key), 0o600);
+        // what was written reads back as a pair to serve with
+        let config = Config {
+            tls_self_signed: false,
+            ..config
+        };
+        assert!(server_config(&config).unwrap().is_some());
+
+        // a second start leaves them be
+        let written = fs::read(&key).unwrap();
+        let err = server_config(&Config {
+            tls_self_signed: true,
+            ..config
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+        assert_eq!(fs::read(&key).unwrap(), written);
+
+        let _ = fs::remove_file(&cert);
+        let _ = fs::remove_file(&key);
+    }
+
+    #[test]
+    fn loads_a_pem_pair() {
+        let (cert, key) = paths("pem_pair");
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        let (certs, _) = load(&cert, &key).unwrap();
+        assert_eq!(certs, [generated.cert.der().clone()]);
+
+        // a key where the certificate should be
+        let err = load(&key, &key).unwrap_err();
+        assert!(err.to_string().contains("no certificate"), "{err}");
+
+        let _ = fs::remove_file(&cert);
+        let _ = fs::remove_file(&key);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::config::Endpoint;
+use crate::load::{LOAD_FREQ, MAX_CATCH_UP_TICKS};
+use rand::Rng;
+use std::collections::{BTreeMap, VecDeque};
+use std::f64::consts::PI;
+
+/// Quantiles reported for every endpoint
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+// how far back the quantiles look, in ticks, like a summary's max age
+const WINDOW_TICKS: usize = 60;
+// spread of the latencies around the median, as a log normal sigma
+const SPREAD: f64 = 0.5;
+// share of requests that hit something slow, a lock or a cold cache
+const SLOW_SHARE: f64 = 0.01;
+// latencies of a tick kept for the quantiles. The requests of a tick are all
+// drawn alike, so beyond that the first ones stand in for the rest
+const SAMPLES_PER_TICK: usize = 200;
+
+/// Latencies of the requests an endpoint served since the box came up
+pub struct Served {
+    pub endpoint: Endpoint,
+    /// cumulative count per upper bound, like a Prometheus histogram
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum_secs: f64,
+    /// the same latencies in exponential buckets, for a native histogram. Per
+    /// bucket index rather than cumulative, empty buckets left out
+    pub native: BTreeMap<i32, u64>,
+    // a sample of the latencies of the last ticks, for the quantiles
+    window: VecDeque<Sample>,
+}
+
+struct Sample {
+    latencies: Vec<f64>,
+    // requests each of the latencies stands for
+    weight: f64,
+}
+
+/// Simulates the requests the box serves, with latencies that go up as the
+/// CPUs get busy, so latency SLOs burn during a CPU saturation.
+pub struct Workload {
+    endpoints: Vec<Served>,
+    /// resolution of the exponential buckets, each bucket is 2^(2^-schema)
+    /// times as wide as the one before
+    pub schema: i32,
+}
+
+impl Workload {
+    pub fn new(endpoints: &[Endpoint], buckets: &[f64], schema: i32) -> Workload {
+        let endpoints = endpoints
+            .iter()
+            .map(|endpoint| Served {
+                endpoint: endpoint.clone(),
+                buckets: buckets.iter().map(|&bound| (bound, 0)).collect(),
+                count: 0,
+                sum_secs: 0.0,
+                native: BTreeMap::new(),
+                window: VecDeque::new(),
+            })
+            .collect();
+
+        Workload { endpoints, schema }
+    }
+
+    /// Serves the requests of that many ticks at `load_share`, one tick
+    /// after the other like the load model
+    pub fn advance(&mut self, rng: &mut impl Rng, ticks: u64, load_share: f64) {
+        for _ in 0..ticks.min(MAX_CATCH_UP_TICKS) {
+            for served in &mut self.endpoints {
+                served.tick(rng, load_share, self.schema);
+            }
+        }
+    }
+
+    /// Starts the counts over, as a restart of the service would
+    pub fn reset(&mut self) {
+        for served in &mut self.endpoints {
+            for (_, count) in &mut served.buckets {
+                *count = 0;
+            }
+            served.count = 0;
+            served.sum_secs = 0.0;
+            served.native.clear();
+            served.window.clear();
+        }
+    }
+
+    pub fn endpoints(&self) -> &[Served] {
+        &self.endpoints
+    }
+}
+
+impl Served {
+    fn tick(&mut self, rng: &mut impl Rng, load_share: f64, schema: i32) {
+        let endpoint = &self.endpoint;
+        let secs = LOAD_FREQ.as_secs_f64();
+        let requests = (endpoint.requests_per_sec * secs * rng.gen_range(0.8..1.2)).round();
+
+        // once there is more work than cores, requests queue up behind it
+        let median = endpoint.median_latency_ms / 1000.0 * (1.0 + load_share.powi(2));
+
+        let mut latencies = Vec::with_capacity(requests as usize);
+        for _ in 0..requests as u64 {
+            let mut latency = median * (SPREAD * standard_normal(rng)).exp();
+            if rng.gen_bool(SLOW_SHARE) {
+                latency *= 10.0;
+            }
+            latencies.push(latency);
+        }
+
+        for &latency in &latencies {
+            self.count += 1;
+            self.sum_secs += latency;
+            for (bound, count) in &mut self.buckets {
+                if latency <= *bound {
+                    *count += 1;
+                }
+            }
+            *self
+                .native
+                .entry(native_index(latency, schema))
+                .or_default() += 1;
+        }
+
+        let weight = latencies.len() as f64 / latencies.len().min(SAMPLES_PER_TICK) as f64;
+        latencies.truncate(SAMPLES_PER_TICK);
+        self.window.push_back(Sample { latencies, weight });
+        if self.window.len() > WINDOW_TICKS {
+            self.window.pop_front();
+        }
+    }
+
+    /// The QUANTILES of the latencies over the last few minutes, in seconds,
+    /// NaN without any requests in that time like a Prometheus summary
+    pub fn quantiles(&self) -> [f64; 3] {
+        let mut latencies: Vec<(f64, f64)> = self
+            .window
+            .iter()
+            .flat_map(|sample| {
+                sample
+                    .latencies
+                    .iter()
+                    .map(|&latency| (latency, sample.weight))
+            })
+            .collect();
+        latencies.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total: f64 = latencies.iter().map(|(_, weight)| weight).sum();
+
+        QUANTILES.map(|quantile| {
+            // the first latency at or past the rank, counting in requests
+            let rank = (quantile * total).ceil();
+            let mut seen = 0.0;
+            latencies
+                .iter()
+                .find(|(_, weight)| {
+                    seen += weight;
+                    seen >= rank
+                })
+                .or(latencies.last())
+                .map_or(f64::NAN, |&(latency, _)| latency)
+        })
+    }
+}
+
+// bucket i of a native histogram holds (base^(i-1), base^i], with
+// base = 2^(2^-schema)
+fn native_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+// Box-Muller, rand itself doesn't come with a normal distribution
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn workload(requests_per_sec: f64) -> Workload {
+        let endpoint = Endpoint {
+            path: "/api".into(),
+            requests_per_sec,
+            median_latency_ms: 100.0,
+        };
+        Workload::new(&[endpoint], &[0.1, 1.0], 3)
+    }
+
+    fn served(ticks: &[(&[f64], f64)]) -> Served {
+        let mut workload = workload(1.0);
+        let mut served = workload.endpoints.remove(0);
+        for &(latencies, weight) in ticks {
+            served.window.push_back(Sample {
+                latencies: latencies.to_vec(),
+                weight,
+            });
+        }
+        served
+    }
+
+    #[test]
+    fn picks_the_quantiles_by_rank() {
+        assert!(served(&[]).quantiles().iter().all(|q| q.is_nan()));
+
+        let latencies: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        let served = served(&[(&latencies[..50], 1.0), (&latencies[50..], 1.0)]);
+        assert_eq!(served.quantiles(), [50.0, 90.0, 99.0]);
+    }
+
+    #[test]
+    fn weighs_the_sampled_ticks_by_their_requests() {
+        // 10 requests at 1s sampled down to one, against 10 of 2s
+        let served = served(&[(&[1.0], 10.0), (&[2.0; 10], 1.0)]);
+        assert_eq!(served.quantiles(), [1.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn keeps_a_bounded_sample_of_busy_endpoints() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut workload = workload(2000.0);
+        workload.advance(&mut rng, WINDOW_TICKS as u64 + 1, 0.0);
+
+        let served = &workload.endpoints()[0];
+        assert_eq!(served.window.len(), WINDOW_TICKS);
+        assert!(served
+            .window
+            .iter()
+            .all(|sample| sample.latencies.len() == SAMPLES_PER_TICK));
+        // every request still counts towards the histogram
+        assert!(served.count > (WINDOW_TICKS as u64 + 1) * 8000);
+
+        // the log normal around the 100ms median, with a slow tail
+        let [p50, p90, p99] = served.quantiles();
+        assert!((p50 - 0.1).abs() < 0.005, "{p50}");
+        assert!(p50 < p90 && p90 < p99, "{p90} {p99}");
+    }
+
+    #[test]
+    fn indexes_native_buckets() {
+        // schema 0 buckets are (2^(i-1), 2^i]
+        assert_eq!(native_index(1.0, 0), 0);
+        assert_eq!(native_index(1.5, 0), 1);
+        assert_eq!(native_index(2.0, 0), 1);
+        assert_eq!(native_index(0.25, 0), -2);
+        assert_eq!(native_index(0.3, 0), -1);
+        // schema 3 splits each of those in eight
+        assert_eq!(native_index(2.0, 3), 8);
+        assert_eq!(native_index(1.01, 3), 1);
+        assert_eq!(native_index(0.5, 3), -8);
+    }
+}